@@ -1,29 +1,25 @@
 #![feature(iter_intersperse)]
 
 use leptos::DynAttrs;
-use leptos::{component, mount_to_body, view, IntoView};
+use leptos::{component, create_action, mount_to_body, view, IntoView, SignalWith};
 use leptos_meta::{provide_meta_context, Html, Meta, Title};
 use leptos_router::{Outlet, Route, Router, Routes, A};
-use leptos_use::use_cookie;
-use leptos_use::utils::FromToStringCodec;
 use once_cell::sync::Lazy;
-use phosphor_leptos::{ArchiveBox, Database, IconWeight, Playlist};
+use phosphor_leptos::{ArchiveBox, Database, IconWeight, Playlist, SignOut};
 
 use crate::pages::archive::Archive;
 use crate::pages::playlists::Playlists;
 use crate::pages::{login::Login, tracklist::TrackList};
+use crate::player::{provide_player, PlayerBar};
+use crate::requests::{logout, provide_current_user, use_csrf_token, use_current_user};
 
 pub mod pages;
+pub mod player;
 pub mod requests;
 
-pub static BASE_API_URL: Lazy<String> = Lazy::new(|| {
-    if !cfg!(debug_assertions) {
-        let base_url = web_sys::window().unwrap().origin();
-        format!("{}/api/", base_url)
-    } else {
-        "http://localhost:7000/".to_string()
-    }
-});
+// The server serves the client and nests the api under /api, `trunk serve` proxies it there
+pub static BASE_API_URL: Lazy<String> =
+    Lazy::new(|| format!("{}/api/", web_sys::window().unwrap().origin()));
 
 fn main() {
     console_error_panic_hook::set_once();
@@ -35,7 +31,9 @@ fn main() {
 #[component]
 pub fn App() -> impl IntoView {
     provide_meta_context();
-    let (api_token, _) = use_cookie::<String, FromToStringCodec>("api_token");
+    provide_player();
+    provide_current_user();
+    let user = use_current_user();
 
     view! {
         <Html lang="en" dir="ltr" attr:data-theme="light"/>
@@ -50,7 +48,7 @@ pub fn App() -> impl IntoView {
                         view=move || {
                             view! {
                                 {move || {
-                                    if !api_token().is_none() {
+                                    if user.with(Option::is_some) {
                                         view! {
                                             <NavBar/>
                                             <Outlet/>
@@ -78,6 +76,11 @@ pub fn App() -> impl IntoView {
 
 #[component]
 pub fn NavBar() -> impl IntoView {
+    let csrf_token = use_csrf_token();
+    let logout_action = create_action(move |_: &()| async move {
+        let _ = logout(csrf_token).await;
+    });
+
     view! {
         <nav id="main_nav">
             <A href="/">
@@ -89,11 +92,21 @@ pub fn NavBar() -> impl IntoView {
             <A href="/archive">
                 <ArchiveBox weight=IconWeight::Regular size="70px" class="hoverable"/>
             </A>
+            <SignOut
+                weight=IconWeight::Regular
+                size="70px"
+                class="hoverable"
+                on:click=move |_| logout_action.dispatch(())
+            />
         </nav>
     }
 }
 
 #[component]
 pub fn Footer() -> impl IntoView {
-    view! { <nav></nav> }
+    view! {
+        <nav id="footer">
+            <PlayerBar/>
+        </nav>
+    }
 }
@@ -1,17 +1,19 @@
 use std::str::FromStr;
 
-use common::candidate::Candidate;
+use common::{
+    candidate::{Candidate, CollectionCandidate},
+    source::Collection,
+};
 use leptos::{
     component, create_action, create_node_ref, create_signal, html, view, IntoView, NodeRef,
     SignalSet,
 };
-use leptos_use::{use_cookie, utils::FromToStringCodec};
 
-use crate::requests::archive_track;
+use crate::requests::{archive_collection, archive_track, use_csrf_token};
 
 #[component]
 pub fn Archive() -> impl IntoView {
-    let (api_token, _) = use_cookie::<String, FromToStringCodec>("api_token");
+    let csrf_token = use_csrf_token();
     let (hint, set_hint) = create_signal(
         String::from_str("Please fill out the fields below and make sure you made no mistakes.")
             .unwrap(),
@@ -20,11 +22,30 @@ pub fn Archive() -> impl IntoView {
     let url_element: NodeRef<html::Input> = create_node_ref();
     let title_element: NodeRef<html::Input> = create_node_ref();
     let artists_element: NodeRef<html::Input> = create_node_ref();
+    let playlist_element: NodeRef<html::Input> = create_node_ref();
     let send_action = create_action(move |candidate: &Candidate| {
         let candidate = candidate.clone();
         async move {
-            match archive_track(api_token, candidate).await {
-                Ok(_) => set_hint.set("Archive request sent successfully!".to_string()),
+            match archive_track(csrf_token, candidate).await {
+                Ok(job_id) => set_hint.set(format!(
+                    "Archive request sent successfully! (Job # {job_id})"
+                )),
+                Err(e) => set_hint.set(format!("Failed to send request: {}", e)),
+            }
+        }
+    });
+
+    let collection_action = create_action(move |collection: &CollectionCandidate| {
+        let collection = collection.clone();
+        async move {
+            set_hint.set("Listing the tracks of the collection...".to_string());
+            match archive_collection(csrf_token, collection).await {
+                Ok(import) => set_hint.set(format!(
+                    "Queued {} tracks! ({} already archived, {} unsupported)",
+                    import.job_ids.len(),
+                    import.already_archived,
+                    import.unsupported
+                )),
                 Err(e) => set_hint.set(format!("Failed to send request: {}", e)),
             }
         }
@@ -34,6 +55,20 @@ pub fn Archive() -> impl IntoView {
         ev.prevent_default();
 
         let url = url_element().unwrap().value().trim().to_owned();
+
+        // Playlists and channels are expanded into their tracks by the server
+        if Collection::recognize(&url).is_some() {
+            let collection = CollectionCandidate {
+                url,
+                create_playlist: playlist_element().unwrap().checked(),
+            };
+            match collection.validated() {
+                Ok(collection) => collection_action.dispatch(collection),
+                Err(e) => set_hint.set(e.to_string()),
+            }
+            return;
+        }
+
         let title = url_element().unwrap().value().trim().to_owned();
         let artists = url_element().unwrap().value().trim().to_owned();
         let mut artists = artists
@@ -72,6 +107,8 @@ pub fn Archive() -> impl IntoView {
                     placeholder="Optional1, Optional2, ..."
                     node_ref=artists_element
                 />
+                <label>Create Playlist (playlist and channel urls only)</label>
+                <input type="checkbox" node_ref=playlist_element/>
                 <button type="submit">SUBMIT</button>
             </form>
         </div>
@@ -1,33 +1,38 @@
-use common::token::ApiToken;
+use common::{
+    api,
+    error::RequestError,
+    user::{Credentials, User},
+};
 use leptos::NodeRef;
 use leptos::{component, create_action, create_node_ref, view, IntoView, SignalSet, SignalWith};
-use leptos_use::use_cookie;
-use leptos_use::utils::FromToStringCodec;
 use phosphor_leptos::IconWeight;
 use phosphor_leptos::Lock;
 
-use crate::requests::REQWEST_CLIENT;
-use crate::BASE_API_URL;
+use crate::requests::{use_current_user, API_CLIENT};
 
 #[component]
 pub fn Login() -> impl IntoView {
-    let input_element: NodeRef<leptos::html::Input> = create_node_ref();
-    let use_secret_action = create_action(|s: &String| use_secret(s.to_owned()));
+    let name_element: NodeRef<leptos::html::Input> = create_node_ref();
+    let password_element: NodeRef<leptos::html::Input> = create_node_ref();
+    let login_action = create_action(|c: &Credentials| login(c.to_owned()));
 
     let on_submit = move |ev: leptos::ev::SubmitEvent| {
         ev.prevent_default();
-        let value = input_element().expect("<input> should be mounted").value();
-        use_secret_action.dispatch(value);
+        let name = name_element().expect("<input> should be mounted").value();
+        let password = password_element()
+            .expect("<input> should be mounted")
+            .value();
+        login_action.dispatch(Credentials { name, password });
     };
-    let token = use_secret_action.value().read_only();
-    let (_, set_api_token) = use_cookie::<String, FromToStringCodec>("api_token");
+    let logged_in = login_action.value().read_only();
+    let current_user = use_current_user();
 
     view! {
         {move || {
-            token
-                .with(|t| {
-                    if let Some(Ok(t)) = t {
-                        set_api_token.set(Some(t.as_str().to_string()));
+            logged_in
+                .with(|u| {
+                    if let Some(Ok(u)) = u {
+                        current_user.set(Some(u.clone()));
                     }
                 })
         }}
@@ -35,11 +40,12 @@ pub fn Login() -> impl IntoView {
         <div id="login_wrapper">
             <span id="login_title">HARMONY</span>
             <form on:submit=on_submit>
+                <input type="text" node_ref=name_element class="login_input" placeholder="name"/>
                 <input
                     type="password"
-                    node_ref=input_element
-                    id="login_secret"
-                    placeholder="secret"
+                    node_ref=password_element
+                    class="login_input"
+                    placeholder="password"
                 />
                 <button type="submit" value="submit">
                     <Lock id="login_lock" weight=IconWeight::Fill size="60px"/>
@@ -49,13 +55,8 @@ pub fn Login() -> impl IntoView {
     }
 }
 
-/// Requests an ApiToken by providing the secret
-async fn use_secret(secret: String) -> anyhow::Result<ApiToken> {
-    let res = REQWEST_CLIENT
-        .post(format!("{}use_secret", BASE_API_URL.as_str()))
-        .body(secret)
-        .send()
-        .await?
-        .error_for_status()?;
-    Ok(serde_json::from_slice(&res.bytes().await?)?)
+/// Logs in with name and password of a user, the server sets the session as cookie
+async fn login(credentials: Credentials) -> Result<User, RequestError> {
+    API_CLIENT.call(api::Login, &credentials).await?;
+    API_CLIENT.call(api::GetCurrentUser, &()).await
 }
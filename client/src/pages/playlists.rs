@@ -1,4 +1,334 @@
-use leptos::{component, IntoView};
+use common::{playlist::Playlist, track::Track};
+use leptos::{
+    component, create_action, create_node_ref, create_resource, create_signal, event_target, html,
+    view, Action, CollectView, IntoView, NodeRef, ReadSignal, SignalGet, SignalSet, WriteSignal,
+};
+use phosphor_leptos::{ArrowDown, ArrowUp, IconWeight, PencilSimple, Trash, X};
+use web_sys::HtmlSelectElement;
 
+use crate::requests::{
+    add_playlist_tracks, create_playlist, delete_playlist, get_all_tracks, get_playlists,
+    order_playlist_tracks, order_playlists, remove_playlist_tracks, rename_playlist,
+    use_csrf_token,
+};
+
+#[derive(Debug, Clone)]
+enum PlaylistEdit {
+    Create(String),
+    Rename(u32, String),
+    Delete(u32),
+    Order(Vec<u32>),
+    OrderTracks(u32, Vec<u32>),
+    RemoveTrack(u32, u32),
+}
+
+#[component]
+pub fn Playlists() -> impl IntoView {
+    let csrf_token = use_csrf_token();
+    let playlist_resource = create_resource(
+        || (),
+        move |_| async move { get_playlists(csrf_token).await },
+    );
+    let track_resource = create_resource(
+        || (),
+        move |_| async move { get_all_tracks(csrf_token).await },
+    );
+    let (selected, set_selected) = create_signal(None::<u32>);
+    let (hint, set_hint) = create_signal(String::new());
+
+    let edit = create_action(move |edit: &PlaylistEdit| {
+        let edit = edit.clone();
+        async move {
+            let result = match edit {
+                PlaylistEdit::Create(name) => create_playlist(csrf_token, name).await.map(|_| ()),
+                PlaylistEdit::Rename(id, name) => rename_playlist(csrf_token, id, name).await,
+                PlaylistEdit::Delete(id) => delete_playlist(csrf_token, id).await,
+                PlaylistEdit::Order(ids) => order_playlists(csrf_token, ids).await,
+                PlaylistEdit::OrderTracks(id, track_ids) => {
+                    order_playlist_tracks(csrf_token, id, track_ids).await
+                }
+                PlaylistEdit::RemoveTrack(id, track_id) => {
+                    remove_playlist_tracks(csrf_token, id, vec![track_id]).await
+                }
+            };
+            match result {
+                Ok(_) => set_hint.set(String::new()),
+                Err(e) => set_hint.set(format!("Failed to edit playlists: {e}")),
+            }
+            playlist_resource.refetch();
+        }
+    });
+
+    let name_element: NodeRef<html::Input> = create_node_ref();
+    let on_submit = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        let input = name_element().expect("<input> should be mounted");
+        let name = input.value().trim().to_owned();
+        if name.is_empty() {
+            set_hint.set("Please enter a name for the playlist.".to_string());
+            return;
+        }
+        input.set_value("");
+        edit.dispatch(PlaylistEdit::Create(name));
+    };
+
+    view! {
+        <div class="playlists">
+            <form class="playlists_create" on:submit=on_submit>
+                <input type="text" placeholder="New playlist" node_ref=name_element/>
+                <button type="submit">CREATE</button>
+            </form>
+            <span class="playlists_hint">{move || hint()}</span>
+            {move || match playlist_resource.get() {
+                Some(Ok(playlists)) => {
+                    let order = playlists.iter().map(|p| p.id).collect::<Vec<_>>();
+                    playlists
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, playlist)| {
+                            let order = order.clone();
+                            view! { <PlaylistListing playlist index order edit set_selected/> }
+                        })
+                        .collect_view()
+                }
+                Some(Err(e)) => format!("Failed loading playlists: {e}").into_view(),
+                None => view! { "LOADING..." }.into_view(),
+            }}
+
+            {move || {
+                let id = selected.get()?;
+                let playlist = playlist_resource
+                    .get()?
+                    .ok()?
+                    .into_iter()
+                    .find(|p| p.id == id)?;
+                let tracks = track_resource.get()?.ok()?;
+                Some(view! { <PlaylistCard playlist tracks edit set_selected/> })
+            }}
+
+        </div>
+    }
+}
+
+#[component]
+fn PlaylistListing(
+    playlist: Playlist,
+    index: usize,
+    order: Vec<u32>,
+    edit: Action<PlaylistEdit, ()>,
+    set_selected: WriteSignal<Option<u32>>,
+) -> impl IntoView {
+    let id = playlist.id;
+    let name = playlist.name.clone();
+    let move_up = moved(&order, index, index.wrapping_sub(1));
+    let move_down = moved(&order, index, index + 1);
+
+    view! {
+        <div class="playlist_listing">
+            <div class="playlist_listing_left" on:click=move |_| set_selected.set(Some(id))>
+                <span class="playlist_name">{playlist.name}</span>
+                <span class="playlist_track_count">
+                    {format!("{} tracks", playlist.track_ids.len())}
+                </span>
+            </div>
+            <div class="playlist_listing_right">
+                <ArrowUp
+                    weight=IconWeight::Regular
+                    size="30px"
+                    class="hoverable"
+                    on:click=move |_| {
+                        if let Some(order) = move_up.clone() {
+                            edit.dispatch(PlaylistEdit::Order(order));
+                        }
+                    }
+                />
+                <ArrowDown
+                    weight=IconWeight::Regular
+                    size="30px"
+                    class="hoverable"
+                    on:click=move |_| {
+                        if let Some(order) = move_down.clone() {
+                            edit.dispatch(PlaylistEdit::Order(order));
+                        }
+                    }
+                />
+                <PencilSimple
+                    weight=IconWeight::Regular
+                    size="30px"
+                    class="hoverable"
+                    on:click=move |_| {
+                        let window = web_sys::window().expect("no global `window` exists");
+                        if let Ok(Some(new_name)) = window
+                            .prompt_with_message_and_default("New playlist name", &name)
+                        {
+                            let new_name = new_name.trim().to_owned();
+                            if !new_name.is_empty() {
+                                edit.dispatch(PlaylistEdit::Rename(id, new_name));
+                            }
+                        }
+                    }
+                />
+                <Trash
+                    weight=IconWeight::Regular
+                    size="30px"
+                    class="hoverable"
+                    on:click=move |_| {
+                        let window = web_sys::window().expect("no global `window` exists");
+                        if let Ok(true) = window.confirm_with_message("Delete this playlist?") {
+                            set_selected.set(None);
+                            edit.dispatch(PlaylistEdit::Delete(id));
+                        }
+                    }
+                />
+            </div>
+        </div>
+    }
+}
+
+#[component]
+fn PlaylistCard(
+    playlist: Playlist,
+    tracks: Vec<Track>,
+    edit: Action<PlaylistEdit, ()>,
+    set_selected: WriteSignal<Option<u32>>,
+) -> impl IntoView {
+    let id = playlist.id;
+    let track_ids = playlist.track_ids.clone();
+
+    view! {
+        <div class="playlist_card_wrapper">
+            <div class="playlist_card">
+                <div class="playlist_card_top">
+                    <X
+                        weight=IconWeight::Regular
+                        size="60px"
+                        class="hoverable"
+                        on:click=move |_| {
+                            set_selected.set(None);
+                        }
+                    />
+
+                    <span class="playlist_card_name">{playlist.name}</span>
+                </div>
+                <div class="playlist_card_tracks">
+                    {playlist
+                        .track_ids
+                        .iter()
+                        .enumerate()
+                        .map(|(index, track_id)| {
+                            let track_id = *track_id;
+                            let track = tracks.iter().find(|t| t.id == track_id);
+                            let title = track
+                                .map(|t| t.title.clone())
+                                .unwrap_or(format!("Track # {track_id}"));
+                            let artists = track
+                                .map(|t| t.artists.join(", "))
+                                .unwrap_or_default();
+                            let move_up = moved(&track_ids, index, index.wrapping_sub(1));
+                            let move_down = moved(&track_ids, index, index + 1);
+                            view! {
+                                <div class="playlist_track">
+                                    <div class="playlist_track_left">
+                                        <span class="track_title">{title}</span>
+                                        <span class="track_artists">{artists}</span>
+                                    </div>
+                                    <div class="playlist_track_right">
+                                        <ArrowUp
+                                            weight=IconWeight::Regular
+                                            size="30px"
+                                            class="hoverable"
+                                            on:click=move |_| {
+                                                if let Some(order) = move_up.clone() {
+                                                    edit.dispatch(PlaylistEdit::OrderTracks(id, order));
+                                                }
+                                            }
+                                        />
+                                        <ArrowDown
+                                            weight=IconWeight::Regular
+                                            size="30px"
+                                            class="hoverable"
+                                            on:click=move |_| {
+                                                if let Some(order) = move_down.clone() {
+                                                    edit.dispatch(PlaylistEdit::OrderTracks(id, order));
+                                                }
+                                            }
+                                        />
+                                        <Trash
+                                            weight=IconWeight::Regular
+                                            size="30px"
+                                            class="hoverable"
+                                            on:click=move |_| {
+                                                edit.dispatch(PlaylistEdit::RemoveTrack(id, track_id));
+                                            }
+                                        />
+                                    </div>
+                                </div>
+                            }
+                        })
+                        .collect_view()}
+                </div>
+            </div>
+        </div>
+    }
+}
+
+/// Select that adds a track to one of the playlists
 #[component]
-pub fn Playlists() -> impl IntoView {}
+pub fn AddToPlaylist(track_id: u32) -> impl IntoView {
+    let csrf_token = use_csrf_token();
+    let playlist_resource = create_resource(
+        || (),
+        move |_| async move { get_playlists(csrf_token).await },
+    );
+    let (hint, set_hint): (ReadSignal<Option<String>>, WriteSignal<Option<String>>) =
+        create_signal(None);
+    let add_action = create_action(move |id: &u32| {
+        let id = *id;
+        async move {
+            match add_playlist_tracks(csrf_token, id, vec![track_id]).await {
+                Ok(_) => set_hint.set(Some("Added to playlist!".to_string())),
+                Err(e) => set_hint.set(Some(format!("Failed to add to playlist: {e}"))),
+            }
+        }
+    });
+
+    view! {
+        <select
+            class="add_to_playlist"
+            on:change=move |ev| {
+                let select = event_target::<HtmlSelectElement>(&ev);
+                if let Ok(id) = select.value().parse::<u32>() {
+                    add_action.dispatch(id);
+                }
+                select.set_value("");
+            }
+        >
+
+            <option value="" selected>
+                {move || hint().unwrap_or("Add to playlist...".to_string())}
+            </option>
+            {move || match playlist_resource.get() {
+                Some(Ok(playlists)) => {
+                    playlists
+                        .into_iter()
+                        .map(|playlist| {
+                            view! { <option value=playlist.id.to_string()>{playlist.name}</option> }
+                        })
+                        .collect_view()
+                }
+                _ => view! {}.into_view(),
+            }}
+
+        </select>
+    }
+}
+
+// Returns ids with the element at from moved to to, None if either is out of bounds
+fn moved(ids: &[u32], from: usize, to: usize) -> Option<Vec<u32>> {
+    if from >= ids.len() || to >= ids.len() {
+        return None;
+    }
+    let mut ids = ids.to_vec();
+    ids.swap(from, to);
+    Some(ids)
+}
@@ -1,39 +1,62 @@
-use common::track::Track;
-use leptos::wasm_bindgen::JsCast;
+use common::error::RequestError;
+use common::track::{SortDirection, Track, TrackPage, TrackQuery, TrackSortField, TrackUpdate};
 use leptos::{
-    component, create_resource, create_signal, view, CollectView, IntoView, ReadSignal, SignalGet,
+    component, create_action, create_node_ref, create_resource, create_signal, event_target_value,
+    html, view, CollectView, IntoView, NodeRef, ReadSignal, Resource, SignalGet,
     SignalGetUntracked, SignalSet, SignalUpdate, WriteSignal,
 };
-use leptos_use::use_cookie;
-use leptos_use::utils::FromToStringCodec;
 use phosphor_leptos::{
-    ArrowCircleLeft, ArrowCircleRight, Download, IconWeight, MagnifyingGlass, PlayCircle, Queue, X,
+    ArrowCircleLeft, ArrowCircleRight, Download, IconWeight, MagnifyingGlass, PlayCircle, Queue,
+    SortAscending, SortDescending, Trash, X,
 };
-use web_sys::{HtmlFormElement, HtmlInputElement};
 
-use crate::requests::get_all_tracks;
-use crate::BASE_API_URL;
+use crate::pages::playlists::AddToPlaylist;
+use crate::player::use_player;
+use crate::requests::{
+    delete_tracks, download_tracks_url, query_tracks, update_track, use_csrf_token,
+};
 
 #[component]
 pub fn TrackList() -> impl IntoView {
-    let (api_token, _) = use_cookie::<String, FromToStringCodec>("api_token");
-    let track_resource = create_resource(
-        || (),
-        move |_| async move { get_all_tracks(api_token).await },
-    );
+    let csrf_token = use_csrf_token();
+    let (query, set_query) = create_signal(String::new());
+    let (sort, set_sort) = create_signal(TrackSortField::Title);
+    let (direction, set_direction) = create_signal(SortDirection::Ascending);
     let (page, set_page) = create_signal(0);
     let (page_count, set_page_count) = create_signal(0);
+    let (edit_mode, set_edit_mode) = create_signal(false);
 
     let page_listing_count = 10;
+    let track_resource = create_resource(
+        move || TrackQuery {
+            query: query(),
+            sort: sort(),
+            direction: direction(),
+            limit: page_listing_count,
+            offset: page() * page_listing_count,
+        },
+        move |track_query| async move { query_tracks(csrf_token, track_query).await },
+    );
     let (viewed_track, set_viewed_track): (ReadSignal<Option<Track>>, WriteSignal<Option<Track>>) =
         create_signal(None);
     view! {
         <div class="track_list">
-            <TrackListFilter/>
+            <TrackListFilter
+                set_query
+                sort
+                set_sort
+                direction
+                set_direction
+                set_page
+                edit_mode
+                set_edit_mode
+            />
             {move || match track_resource.get() {
-                Some(Ok(tracks)) => {
-                    set_page_count.set(tracks.len() as u32 / page_listing_count + 1);
-                    tracks
+                Some(Ok(track_page)) => {
+                    set_page_count
+                        .set((track_page.total + page_listing_count - 1) / page_listing_count);
+                    track_page
+                        .tracks
                         .into_iter()
                         .map(|track| {
                             view! { <TrackListing track set_viewed_track/> }
@@ -46,7 +69,14 @@ pub fn TrackList() -> impl IntoView {
 
             {move || {
                 if viewed_track.get().is_some() {
-                    view! { <TrackCard track=viewed_track.get().unwrap() set_viewed_track/> }
+                    view! {
+                        <TrackCard
+                            track=viewed_track.get().unwrap()
+                            set_viewed_track
+                            edit_mode
+                            track_resource
+                        />
+                    }
                 } else {
                     view! {}.into_view()
                 }
@@ -85,8 +115,46 @@ pub fn TrackListing(track: Track, set_viewed_track: WriteSignal<Option<Track>>)
 }
 
 #[component]
-pub fn TrackCard(track: Track, set_viewed_track: WriteSignal<Option<Track>>) -> impl IntoView {
-    let (api_token, _) = use_cookie::<String, FromToStringCodec>("api_token");
+pub fn TrackCard(
+    track: Track,
+    set_viewed_track: WriteSignal<Option<Track>>,
+    edit_mode: ReadSignal<bool>,
+    track_resource: Resource<TrackQuery, Result<TrackPage, RequestError>>,
+) -> impl IntoView {
+    let csrf_token = use_csrf_token();
+    let player = use_player();
+    let delete_action = create_action(move |id: &u32| {
+        let id = *id;
+        async move {
+            match delete_tracks(csrf_token, vec![id]).await {
+                Ok(_) => {
+                    set_viewed_track.set(None);
+                    track_resource.refetch();
+                }
+                Err(e) => {
+                    let window = web_sys::window().expect("no global `window` exists");
+                    let _ = window.alert_with_message(&format!("Failed to delete track: {}", e));
+                }
+            }
+        }
+    });
+    let download_action = create_action(move |id: &u32| {
+        let id = *id;
+        async move {
+            let window = web_sys::window().expect("no global `window` exists");
+            // The response is an attachment, so the browser saves it and stays on the page
+            let result = match download_tracks_url(csrf_token, vec![id]).await {
+                Ok(url) => window
+                    .location()
+                    .set_href(&url)
+                    .map_err(|e| format!("{e:?}")),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = result {
+                let _ = window.alert_with_message(&format!("Failed to download track: {}", e));
+            }
+        }
+    });
     view! {
         <div class="track_card_wrapper">
             <div class="track_card">
@@ -107,80 +175,233 @@ pub fn TrackCard(track: Track, set_viewed_track: WriteSignal<Option<Track>>) ->
                 </div>
 
                 <div class="track_card_play_actions">
-                    <PlayCircle weight=IconWeight::Regular size="60%" class="hoverable"/>
-                    <Queue weight=IconWeight::Regular size="60%" class="hoverable"/>
+                    <PlayCircle
+                        weight=IconWeight::Regular
+                        size="60%"
+                        class="hoverable"
+                        on:click={
+                            let track = track.clone();
+                            move |_| player.play_now(track.clone())
+                        }
+                    />
+
+                    <Queue
+                        weight=IconWeight::Regular
+                        size="60%"
+                        class="hoverable"
+                        on:click={
+                            let track = track.clone();
+                            move |_| player.enqueue(track.clone())
+                        }
+                    />
+
                     <Download
+                        weight=IconWeight::Regular
+                        size="60%"
+                        class="hoverable"
+                        on:click=move |_| download_action.dispatch(track.id)
+                    />
+
+                    <Trash
                         weight=IconWeight::Regular
                         size="60%"
                         class="hoverable"
                         on:click=move |_| {
                             let window = web_sys::window().expect("no global `window` exists");
-                            let document = window
-                                .document()
-                                .expect("should have a document on window");
-                            let body = document.body().expect("document should have a body");
-                            let single_track_download_form: web_sys::Element = document
-                                .get_element_by_id("single_track_download_form")
-                                .expect(
-                                    "Expected invisible_form input with id: single_track_download_form to exist",
-                                );
-                            let single_track_download_form = single_track_download_form
-                                .dyn_into::<HtmlFormElement>()
-                                .unwrap();
-                            let input_ids: web_sys::Element = document
-                                .get_element_by_id("track_ids_to_download")
-                                .expect(
-                                    "Expected invisible_form input with id: track_ids_to_download to exist",
-                                );
-                            let input_ids = input_ids.dyn_into::<HtmlInputElement>().unwrap();
-                            input_ids.set_value(&serde_json::to_string(&vec![track.id]).unwrap());
-                            single_track_download_form.submit().unwrap();
+                            if let Ok(true) = window
+                                .confirm_with_message("Move this track to the trash?")
+                            {
+                                delete_action.dispatch(track.id);
+                            }
                         }
                     />
 
                 </div>
-                <span class="track_card_title">{track.title}</span>
-                <span class="track_card_artists">
-                    {track.artists.into_iter().intersperse(", ".to_string()).collect::<String>()}
-                </span>
+                {
+                    let track = track.clone();
+                    move || {
+                        if edit_mode() {
+                            view! {
+                                <TrackEditForm
+                                    track=track.clone()
+                                    set_viewed_track
+                                    track_resource
+                                />
+                            }
+                                .into_view()
+                        } else {
+                            view! {
+                                <span class="track_card_title">{track.title.clone()}</span>
+                                <span class="track_card_artists">
+                                    {track
+                                        .artists
+                                        .iter()
+                                        .cloned()
+                                        .intersperse(", ".to_string())
+                                        .collect::<String>()}
+                                </span>
+                            }
+                                .into_view()
+                        }
+                    }
+                }
                 <a target="_blank" href=format!("https://{}", track.url) class="track_card_url">
                     {track.url}
                 </a>
+                <AddToPlaylist track_id=track.id/>
             </div>
         </div>
-        <form
-            id="single_track_download_form"
-            target="_blank"
-            class="invisible_form"
-            method="post"
-            action=format!(
-                "{}download_tracks?api_token={}",
-                *BASE_API_URL,
-                api_token.get_untracked().unwrap_or("".to_string()),
-            )
-        >
+    }
+}
+
+#[component]
+pub fn TrackEditForm(
+    track: Track,
+    set_viewed_track: WriteSignal<Option<Track>>,
+    track_resource: Resource<TrackQuery, Result<TrackPage, RequestError>>,
+) -> impl IntoView {
+    let csrf_token = use_csrf_token();
+    let (hint, set_hint) = create_signal(String::new());
+    let title_element: NodeRef<html::Input> = create_node_ref();
+    let artists_element: NodeRef<html::Input> = create_node_ref();
 
-            <input id="track_ids_to_download" name="ids" type="hidden" value=""/>
+    let id = track.id;
+    let update_action = create_action(move |update: &TrackUpdate| {
+        let update = update.clone();
+        async move {
+            match update_track(csrf_token, id, update).await {
+                Ok(track) => {
+                    set_viewed_track.set(Some(track));
+                    track_resource.refetch();
+                }
+                Err(e) => set_hint.set(format!("Failed to update track: {}", e)),
+            }
+        }
+    });
+
+    let on_submit = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        let title = title_element().unwrap().value();
+        let artists = artists_element()
+            .unwrap()
+            .value()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .collect::<Vec<String>>();
+        match (TrackUpdate { title, artists }).validated() {
+            Ok(update) => update_action.dispatch(update),
+            Err(e) => set_hint.set(e.to_string()),
+        }
+    };
+
+    view! {
+        <form class="track_edit_form" on:submit=on_submit>
+            <span class="track_edit_form_hint">{move || hint()}</span>
+            <label>Track Title</label>
+            <input type="text" value=track.title node_ref=title_element/>
+            <label>Artists</label>
+            <input type="text" value=track.artists.join(", ") node_ref=artists_element/>
+            <button type="submit">SAVE</button>
         </form>
     }
 }
 
 #[component]
-pub fn TrackListFilter() -> impl IntoView {
+pub fn TrackListFilter(
+    set_query: WriteSignal<String>,
+    sort: ReadSignal<TrackSortField>,
+    set_sort: WriteSignal<TrackSortField>,
+    direction: ReadSignal<SortDirection>,
+    set_direction: WriteSignal<SortDirection>,
+    set_page: WriteSignal<u32>,
+    edit_mode: ReadSignal<bool>,
+    set_edit_mode: WriteSignal<bool>,
+) -> impl IntoView {
+    let query_element: NodeRef<html::Input> = create_node_ref();
+    let search = move || {
+        let query = query_element().expect("<input> should be mounted").value();
+        set_query.set(query.trim().to_owned());
+        set_page.set(0);
+    };
+
     view! {
         <div class="track_list_filter">
             <div class="track_list_filter_row0">
-                <input type="text"/>
-                <MagnifyingGlass weight=IconWeight::Bold size="40px" class="hoverable"/>
+                <input type="text" node_ref=query_element on:change=move |_| search()/>
+                <MagnifyingGlass
+                    weight=IconWeight::Bold
+                    size="40px"
+                    class="hoverable"
+                    on:click=move |_| search()
+                />
+                {move || match direction() {
+                    SortDirection::Ascending => {
+                        view! {
+                            <SortAscending
+                                weight=IconWeight::Bold
+                                size="40px"
+                                class="hoverable"
+                                on:click=move |_| {
+                                    set_direction.set(SortDirection::Descending);
+                                    set_page.set(0);
+                                }
+                            />
+                        }
+                            .into_view()
+                    }
+                    SortDirection::Descending => {
+                        view! {
+                            <SortDescending
+                                weight=IconWeight::Bold
+                                size="40px"
+                                class="hoverable"
+                                on:click=move |_| {
+                                    set_direction.set(SortDirection::Ascending);
+                                    set_page.set(0);
+                                }
+                            />
+                        }
+                            .into_view()
+                    }
+                }}
+
             </div>
             <div class="track_list_filter_row1">
-                <select>
-                    <option>Track Title</option>
-                    <option>First Artist</option>
-                    <option>Date Archived</option>
-                    <option>Origin URL</option>
+                <select on:change=move |ev| {
+                    let sort = match event_target_value(&ev).as_str() {
+                        "first_artist" => TrackSortField::FirstArtist,
+                        "date_archived" => TrackSortField::DateArchived,
+                        "url" => TrackSortField::Url,
+                        _ => TrackSortField::Title,
+                    };
+                    set_sort.set(sort);
+                    set_page.set(0);
+                }>
+                    <option value="title" selected=move || sort() == TrackSortField::Title>
+                        Track Title
+                    </option>
+                    <option
+                        value="first_artist"
+                        selected=move || sort() == TrackSortField::FirstArtist
+                    >
+                        First Artist
+                    </option>
+                    <option
+                        value="date_archived"
+                        selected=move || sort() == TrackSortField::DateArchived
+                    >
+                        Date Archived
+                    </option>
+                    <option value="url" selected=move || sort() == TrackSortField::Url>
+                        Origin URL
+                    </option>
                 </select>
-                <button>Edit Mode</button>
+                <button
+                    class:track_list_filter_active=move || edit_mode()
+                    on:click=move |_| set_edit_mode.update(|v| *v = !*v)
+                >
+                    Edit Mode
+                </button>
             </div>
         </div>
     }
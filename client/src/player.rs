@@ -0,0 +1,487 @@
+use common::track::Track;
+use leptos::{
+    component, create_effect, create_rw_signal, create_signal, event_target_value, expect_context,
+    html, provide_context, view, CollectView, IntoView, NodeRef, RwSignal, SignalGet,
+    SignalGetUntracked, SignalSet, SignalUpdate, SignalWith, SignalWithUntracked,
+};
+use phosphor_leptos::{
+    IconWeight, Pause, Play, Queue, Repeat, RepeatOnce, Shuffle, SkipBack, SkipForward, Trash,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::requests::stream_track_url;
+
+const STORAGE_KEY: &str = "harmony_play_queue";
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    All,
+    One,
+}
+
+/// Tracks to play, persisted in local storage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayQueue {
+    pub tracks: Vec<Track>,
+    pub current: Option<usize>,
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+    pub volume: f64,
+}
+
+impl Default for PlayQueue {
+    fn default() -> Self {
+        Self {
+            tracks: Vec::new(),
+            current: None,
+            shuffle: false,
+            repeat: RepeatMode::Off,
+            volume: 1.0,
+        }
+    }
+}
+
+impl PlayQueue {
+    pub fn current_track(&self) -> Option<&Track> {
+        self.tracks.get(self.current?)
+    }
+
+    // Appends the track, returns true if it became the current track
+    pub fn push(&mut self, track: Track) -> bool {
+        self.tracks.push(track);
+        if self.current.is_none() {
+            self.current = Some(self.tracks.len() - 1);
+            return true;
+        }
+        false
+    }
+
+    // Inserts the track after the current one and makes it current
+    pub fn play_now(&mut self, track: Track) {
+        let index = match self.current {
+            Some(current) => current + 1,
+            None => self.tracks.len(),
+        };
+        self.tracks.insert(index, track);
+        self.current = Some(index);
+    }
+
+    pub fn select(&mut self, index: usize) -> bool {
+        if index >= self.tracks.len() {
+            return false;
+        }
+        self.current = Some(index);
+        true
+    }
+
+    // Removes the track at index, returns true if the current track changed
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.tracks.len() {
+            return false;
+        }
+        self.tracks.remove(index);
+        match self.current {
+            Some(current) if current > index => {
+                self.current = Some(current - 1);
+                false
+            }
+            Some(current) if current == index => {
+                self.current = if self.tracks.is_empty() {
+                    None
+                } else {
+                    Some(current.min(self.tracks.len() - 1))
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Moves to the next track, random has to be in [0, 1) and is used for shuffling.
+    // Returns false if the end of the queue was reached.
+    pub fn next(&mut self, random: f64) -> bool {
+        let Some(current) = self.current else {
+            return false;
+        };
+        let len = self.tracks.len();
+        if self.shuffle && len > 1 {
+            // Pick any track except the current one
+            let offset = 1 + (random * (len - 1) as f64) as usize % (len - 1);
+            self.current = Some((current + offset) % len);
+        } else if current + 1 < len {
+            self.current = Some(current + 1);
+        } else if self.repeat == RepeatMode::All {
+            self.current = Some(0);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    pub fn previous(&mut self) {
+        let Some(current) = self.current else {
+            return;
+        };
+        self.current = if current > 0 {
+            Some(current - 1)
+        } else if self.repeat == RepeatMode::All {
+            Some(self.tracks.len() - 1)
+        } else {
+            Some(0)
+        };
+    }
+
+    fn load() -> Self {
+        web_sys::window()
+            .and_then(|window| window.local_storage().ok().flatten())
+            .and_then(|storage| storage.get_item(STORAGE_KEY).ok().flatten())
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        let storage = web_sys::window().and_then(|window| window.local_storage().ok().flatten());
+        if let Some(storage) = storage {
+            let _ = storage.set_item(STORAGE_KEY, &serde_json::to_string(self).unwrap());
+        }
+    }
+}
+
+/// Shared playback state, provided as context by provide_player
+#[derive(Clone, Copy)]
+pub struct Player {
+    pub queue: RwSignal<PlayQueue>,
+    pub playing: RwSignal<bool>,
+    audio: NodeRef<html::Audio>,
+}
+
+impl Player {
+    pub fn enqueue(&self, track: Track) {
+        let mut changed = false;
+        self.queue.update(|q| changed = q.push(track));
+        if changed {
+            self.load();
+        }
+    }
+
+    pub fn play_now(&self, track: Track) {
+        self.queue.update(|q| q.play_now(track));
+        self.playing.set(true);
+        self.load();
+    }
+
+    pub fn select(&self, index: usize) {
+        let mut changed = false;
+        self.queue.update(|q| changed = q.select(index));
+        if changed {
+            self.playing.set(true);
+            self.load();
+        }
+    }
+
+    pub fn remove(&self, index: usize) {
+        let mut changed = false;
+        self.queue.update(|q| changed = q.remove(index));
+        if changed {
+            self.load();
+        }
+    }
+
+    pub fn next(&self) {
+        let mut changed = false;
+        self.queue
+            .update(|q| changed = q.next(js_sys::Math::random()));
+        if changed {
+            self.load();
+        }
+    }
+
+    pub fn previous(&self) {
+        let Some(audio) = self.audio.get_untracked() else {
+            return;
+        };
+        // Restart the track if it has been playing for a while
+        if audio.current_time() > 3.0 {
+            audio.set_current_time(0.0);
+            return;
+        }
+        self.queue.update(|q| q.previous());
+        self.load();
+    }
+
+    pub fn toggle(&self) {
+        let Some(audio) = self.audio.get_untracked() else {
+            return;
+        };
+        if self.playing.get_untracked() {
+            let _ = audio.pause();
+        } else {
+            let _ = audio.play();
+        }
+    }
+
+    // Points the audio element at the current track
+    fn load(&self) {
+        let Some(audio) = self.audio.get_untracked() else {
+            return;
+        };
+        match self
+            .queue
+            .with_untracked(|q| q.current_track().map(|t| t.id))
+        {
+            Some(id) => {
+                audio.set_src(&stream_track_url(id));
+                if self.playing.get_untracked() {
+                    let _ = audio.play();
+                }
+            }
+            None => {
+                let _ = audio.pause();
+                let _ = audio.remove_attribute("src");
+            }
+        }
+    }
+
+    // Called when the current track finished playing
+    fn ended(&self) {
+        let Some(audio) = self.audio.get_untracked() else {
+            return;
+        };
+        if self.queue.with_untracked(|q| q.repeat) == RepeatMode::One {
+            audio.set_current_time(0.0);
+            let _ = audio.play();
+            return;
+        }
+        let mut changed = false;
+        self.queue
+            .update(|q| changed = q.next(js_sys::Math::random()));
+        if changed {
+            self.playing.set(true);
+            self.load();
+        }
+    }
+}
+
+pub fn provide_player() {
+    let queue = create_rw_signal(PlayQueue::load());
+    create_effect(move |_| queue.with(|q| q.save()));
+    provide_context(Player {
+        queue,
+        playing: create_rw_signal(false),
+        audio: NodeRef::new(),
+    });
+}
+
+pub fn use_player() -> Player {
+    expect_context::<Player>()
+}
+
+#[component]
+pub fn PlayerBar() -> impl IntoView {
+    let player = use_player();
+    let queue = player.queue;
+    let (current_time, set_current_time) = create_signal(0.0);
+    let (duration, set_duration) = create_signal(0.0);
+    let (show_queue, set_show_queue) = create_signal(false);
+
+    // Load the persisted track once the audio element exists
+    create_effect(move |_| {
+        if player.audio.get().is_some() {
+            player.load();
+        }
+    });
+    create_effect(move |_| {
+        let volume = queue.with(|q| q.volume);
+        if let Some(audio) = player.audio.get() {
+            audio.set_volume(volume);
+        }
+    });
+
+    view! {
+        <audio
+            node_ref=player.audio
+            on:play=move |_| player.playing.set(true)
+            on:pause=move |_| player.playing.set(false)
+            on:ended=move |_| player.ended()
+            on:timeupdate=move |_| {
+                if let Some(audio) = player.audio.get_untracked() {
+                    set_current_time.set(audio.current_time());
+                }
+            }
+            on:durationchange=move |_| {
+                if let Some(audio) = player.audio.get_untracked() {
+                    let duration = audio.duration();
+                    set_duration.set(if duration.is_finite() { duration } else { 0.0 });
+                }
+            }
+        ></audio>
+
+        {move || {
+            show_queue()
+                .then(|| {
+                    view! {
+                        <div class="play_queue">
+                            {move || {
+                                let current = queue.with(|q| q.current);
+                                queue
+                                    .with(|q| q.tracks.clone())
+                                    .into_iter()
+                                    .enumerate()
+                                    .map(|(index, track)| {
+                                        view! {
+                                            <div
+                                                class="play_queue_track"
+                                                class:play_queue_current=current == Some(index)
+                                            >
+                                                <span
+                                                    class="play_queue_track_title"
+                                                    on:click=move |_| player.select(index)
+                                                >
+                                                    {format!("{} - {}", track.artists.join(", "), track.title)}
+                                                </span>
+                                                <Trash
+                                                    weight=IconWeight::Regular
+                                                    size="24px"
+                                                    class="hoverable"
+                                                    on:click=move |_| player.remove(index)
+                                                />
+                                            </div>
+                                        }
+                                    })
+                                    .collect_view()
+                            }}
+
+                        </div>
+                    }
+                })
+        }}
+
+        <div class="player_bar">
+            <span class="player_bar_title">
+                {move || {
+                    queue
+                        .with(|q| {
+                            q.current_track()
+                                .map(|t| format!("{} - {}", t.artists.join(", "), t.title))
+                        })
+                        .unwrap_or_default()
+                }}
+
+            </span>
+            <div class="player_bar_controls">
+                <span class:player_bar_active=move || queue.with(|q| q.shuffle)>
+                    <Shuffle
+                        weight=IconWeight::Regular
+                        size="30px"
+                        class="hoverable"
+                        on:click=move |_| queue.update(|q| q.shuffle = !q.shuffle)
+                    />
+                </span>
+                <SkipBack
+                    weight=IconWeight::Fill
+                    size="30px"
+                    class="hoverable"
+                    on:click=move |_| player.previous()
+                />
+                {move || {
+                    if player.playing.get() {
+                        view! {
+                            <Pause
+                                weight=IconWeight::Fill
+                                size="40px"
+                                class="hoverable"
+                                on:click=move |_| player.toggle()
+                            />
+                        }
+                            .into_view()
+                    } else {
+                        view! {
+                            <Play
+                                weight=IconWeight::Fill
+                                size="40px"
+                                class="hoverable"
+                                on:click=move |_| player.toggle()
+                            />
+                        }
+                            .into_view()
+                    }
+                }}
+
+                <SkipForward
+                    weight=IconWeight::Fill
+                    size="30px"
+                    class="hoverable"
+                    on:click=move |_| player.next()
+                />
+                <span
+                    class:player_bar_active=move || queue.with(|q| q.repeat != RepeatMode::Off)
+                    on:click=move |_| {
+                        queue
+                            .update(|q| {
+                                q.repeat = match q.repeat {
+                                    RepeatMode::Off => RepeatMode::All,
+                                    RepeatMode::All => RepeatMode::One,
+                                    RepeatMode::One => RepeatMode::Off,
+                                };
+                            })
+                    }
+                >
+
+                    {move || {
+                        if queue.with(|q| q.repeat) == RepeatMode::One {
+                            view! { <RepeatOnce weight=IconWeight::Regular size="30px" class="hoverable"/> }
+                                .into_view()
+                        } else {
+                            view! { <Repeat weight=IconWeight::Regular size="30px" class="hoverable"/> }
+                                .into_view()
+                        }
+                    }}
+
+                </span>
+                <span class:player_bar_active=move || show_queue()>
+                    <Queue
+                        weight=IconWeight::Regular
+                        size="30px"
+                        class="hoverable"
+                        on:click=move |_| set_show_queue.update(|v| *v = !*v)
+                    />
+                </span>
+            </div>
+            <div class="player_bar_sliders">
+                <input
+                    type="range"
+                    class="player_bar_seek"
+                    min="0"
+                    step="any"
+                    max=move || duration()
+                    prop:value=move || current_time()
+                    on:input=move |ev| {
+                        if let (Some(audio), Ok(time)) = (
+                            player.audio.get_untracked(),
+                            event_target_value(&ev).parse::<f64>(),
+                        ) {
+                            audio.set_current_time(time);
+                        }
+                    }
+                />
+
+                <input
+                    type="range"
+                    class="player_bar_volume"
+                    min="0"
+                    max="1"
+                    step="0.01"
+                    prop:value=move || queue.with(|q| q.volume)
+                    on:input=move |ev| {
+                        if let Ok(volume) = event_target_value(&ev).parse::<f64>() {
+                            queue.update(|q| q.volume = volume);
+                        }
+                    }
+                />
+
+            </div>
+        </div>
+    }
+}
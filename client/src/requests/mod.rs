@@ -1,80 +1,237 @@
-use anyhow::Context;
-use common::{candidate::Candidate, track::Track};
-use leptos::{Signal, SignalGet, SignalGetUntracked, SignalSet};
+use common::{
+    api::{
+        AddPlaylistTracks, ArchiveCollection, ArchiveTrack, CreateDownloadUrl, CreatePlaylist,
+        DeletePlaylist, DeleteTracks, DownloadSignedTracks, GetAllTracks, GetCurrentUser,
+        GetPlaylists, Logout, OrderPlaylistTracks, OrderPlaylists, QueryTracks,
+        RemovePlaylistTracks, RenamePlaylist, StreamTrack, UpdateTrack, CSRF_COOKIE,
+    },
+    archive_job::CollectionImport,
+    candidate::{Candidate, CollectionCandidate},
+    client::{ApiClient, Credential},
+    error::{ErrorKind, RequestError},
+    playlist::Playlist,
+    track::{Track, TrackPage, TrackQuery, TrackUpdate},
+    user::User,
+};
+use leptos::{
+    create_rw_signal, expect_context, provide_context, spawn_local, use_context, RwSignal, Signal,
+    SignalGetUntracked, SignalSet,
+};
 use leptos_use::{use_cookie, utils::FromToStringCodec};
 use once_cell::sync::Lazy;
-use reqwest::Client;
 
 use crate::BASE_API_URL;
 
-pub static REQWEST_CLIENT: Lazy<Client> = Lazy::new(|| Client::builder().build().unwrap());
+pub static API_CLIENT: Lazy<ApiClient> = Lazy::new(|| ApiClient::new(&BASE_API_URL));
 
-pub fn reset_token_if_needed(error_string: &str) {
-    if error_string.contains("401 Unauthorized") {
-        let (_, set_api_token) = use_cookie::<String, FromToStringCodec>("api_token");
-        set_api_token.set(None);
-    }
+/// The user of the session, None while logged out. Provided as context by provide_current_user.
+#[derive(Clone, Copy)]
+pub struct CurrentUser(pub RwSignal<Option<User>>);
+
+// The session is the HttpOnly api_token cookie, which the client can not read, so it asks the
+// server whether the cookie still belongs to a user
+pub fn provide_current_user() {
+    let user = create_rw_signal(None);
+    provide_context(CurrentUser(user));
+    spawn_local(async move {
+        if let Ok(current) = API_CLIENT.call(GetCurrentUser, &()).await {
+            user.set(Some(current));
+        }
+    });
+}
+
+pub fn use_current_user() -> RwSignal<Option<User>> {
+    expect_context::<CurrentUser>().0
 }
 
-pub async fn get_all_tracks(api_token: Signal<Option<String>>) -> Result<Vec<Track>, String> {
-    match get_all_tracks_inner(api_token).await {
-        Ok(tracks) => Ok(tracks),
-        Err(e) => {
-            let cause = e.to_string();
-            reset_token_if_needed(&cause);
-            Err(cause)
+// The CSRF token login sets next to the session cookie, None while logged out
+pub fn use_csrf_token() -> Signal<Option<String>> {
+    use_cookie::<String, FromToStringCodec>(CSRF_COOKIE).0
+}
+
+// The client sending requests with the session of the browser
+fn api(csrf_token: Signal<Option<String>>) -> ApiClient {
+    API_CLIENT
+        .clone()
+        .with_credential(csrf_token.get_untracked().map(Credential::Session))
+}
+
+// Forgets the user once the server no longer accepts the session, which leads back to the login
+pub fn logout_if_unauthorized(error: &RequestError) {
+    if error.kind() == Some(ErrorKind::Unauthorized) {
+        if let Some(CurrentUser(user)) = use_context::<CurrentUser>() {
+            user.set(None);
         }
     }
 }
 
-async fn get_all_tracks_inner(api_token: Signal<Option<String>>) -> anyhow::Result<Vec<Track>> {
-    let response = REQWEST_CLIENT
-        .get(format!("{}get_all_tracks", *BASE_API_URL))
-        .header(
-            "api_token",
-            api_token.get_untracked().context("No api_token set")?,
-        )
-        .send()
-        .await?;
-
-    response.error_for_status_ref()?;
+// Url a track can be streamed from, e.g. by an <audio> element. The client is served from the
+// origin of the server, so the browser authenticates it with the api_token cookie.
+pub fn stream_track_url(id: u32) -> String {
+    API_CLIENT.url(&StreamTrack(id))
+}
 
-    let bytes = response.bytes().await?;
+// Url the browser downloads the tracks from, signed by the server so it holds no credential
+pub async fn download_tracks_url(
+    csrf_token: Signal<Option<String>>,
+    ids: Vec<u32>,
+) -> Result<String, RequestError> {
+    let download = api(csrf_token)
+        .call(CreateDownloadUrl, &ids)
+        .await
+        .inspect_err(logout_if_unauthorized)?;
+    API_CLIENT.query_url(&DownloadSignedTracks, &download)
+}
 
-    let mut tracks: Vec<Track> = serde_json::from_slice(&bytes)?;
+pub async fn get_all_tracks(
+    csrf_token: Signal<Option<String>>,
+) -> Result<Vec<Track>, RequestError> {
+    let mut tracks = api(csrf_token)
+        .call(GetAllTracks, &())
+        .await
+        .inspect_err(logout_if_unauthorized)?;
     tracks.sort_unstable_by(|a, b| a.title.to_lowercase().cmp(&b.title.to_lowercase()));
-
     Ok(tracks)
 }
 
+pub async fn query_tracks(
+    csrf_token: Signal<Option<String>>,
+    track_query: TrackQuery,
+) -> Result<TrackPage, RequestError> {
+    api(csrf_token)
+        .call(QueryTracks, &track_query)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn update_track(
+    csrf_token: Signal<Option<String>>,
+    id: u32,
+    update: TrackUpdate,
+) -> Result<Track, RequestError> {
+    api(csrf_token)
+        .call(UpdateTrack(id), &update)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn delete_tracks(
+    csrf_token: Signal<Option<String>>,
+    ids: Vec<u32>,
+) -> Result<(), RequestError> {
+    api(csrf_token)
+        .call(DeleteTracks, &ids)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
 pub async fn archive_track(
-    api_token: Signal<Option<String>>,
+    csrf_token: Signal<Option<String>>,
     candidate: Candidate,
-) -> Result<(), String> {
-    match archive_track_inner(api_token, candidate).await {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            let cause = e.to_string();
-            reset_token_if_needed(&cause);
-            Err(cause)
-        }
-    }
+) -> Result<u32, RequestError> {
+    api(csrf_token)
+        .call(ArchiveTrack, &candidate)
+        .await
+        .inspect_err(logout_if_unauthorized)
 }
 
-async fn archive_track_inner(
-    api_token: Signal<Option<String>>,
-    candidate: Candidate,
-) -> anyhow::Result<()> {
-    let response = REQWEST_CLIENT
-        .post(format!("{}archive_track", *BASE_API_URL))
-        .body(serde_json::to_string(&candidate)?)
-        .header(
-            "api_token",
-            api_token.get_untracked().context("No api_token set")?,
-        )
-        .send()
-        .await?;
-
-    response.error_for_status_ref()?;
-    Ok(())
+pub async fn archive_collection(
+    csrf_token: Signal<Option<String>>,
+    collection: CollectionCandidate,
+) -> Result<CollectionImport, RequestError> {
+    api(csrf_token)
+        .call(ArchiveCollection, &collection)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn get_playlists(
+    csrf_token: Signal<Option<String>>,
+) -> Result<Vec<Playlist>, RequestError> {
+    api(csrf_token)
+        .call(GetPlaylists, &())
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn create_playlist(
+    csrf_token: Signal<Option<String>>,
+    name: String,
+) -> Result<u32, RequestError> {
+    api(csrf_token)
+        .call(CreatePlaylist, &name)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn rename_playlist(
+    csrf_token: Signal<Option<String>>,
+    id: u32,
+    name: String,
+) -> Result<(), RequestError> {
+    api(csrf_token)
+        .call(RenamePlaylist(id), &name)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn delete_playlist(
+    csrf_token: Signal<Option<String>>,
+    id: u32,
+) -> Result<(), RequestError> {
+    api(csrf_token)
+        .call(DeletePlaylist(id), &())
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn order_playlists(
+    csrf_token: Signal<Option<String>>,
+    ids: Vec<u32>,
+) -> Result<(), RequestError> {
+    api(csrf_token)
+        .call(OrderPlaylists, &ids)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn add_playlist_tracks(
+    csrf_token: Signal<Option<String>>,
+    id: u32,
+    track_ids: Vec<u32>,
+) -> Result<(), RequestError> {
+    api(csrf_token)
+        .call(AddPlaylistTracks(id), &track_ids)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn remove_playlist_tracks(
+    csrf_token: Signal<Option<String>>,
+    id: u32,
+    track_ids: Vec<u32>,
+) -> Result<(), RequestError> {
+    api(csrf_token)
+        .call(RemovePlaylistTracks(id), &track_ids)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+pub async fn order_playlist_tracks(
+    csrf_token: Signal<Option<String>>,
+    id: u32,
+    track_ids: Vec<u32>,
+) -> Result<(), RequestError> {
+    api(csrf_token)
+        .call(OrderPlaylistTracks(id), &track_ids)
+        .await
+        .inspect_err(logout_if_unauthorized)
+}
+
+// Revokes the session, the user is forgotten even if that fails
+pub async fn logout(csrf_token: Signal<Option<String>>) -> Result<(), RequestError> {
+    let user = use_current_user();
+    let result = api(csrf_token).call(Logout, &()).await;
+    user.set(None);
+    result
 }
@@ -0,0 +1,312 @@
+//! Endpoints of the server. Each one names its route, its method and the types of its request
+//! and response, so the server and its clients can not disagree on them. Routes are relative to
+//! the base url of the server and start with their version.
+
+use std::num::ParseIntError;
+
+use itertools::Itertools;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    archive_job::{ArchiveJob, CollectionImport},
+    candidate::{Candidate, CollectionCandidate},
+    playlist::Playlist,
+    token::{AccessToken, CreatedAccessToken, NewAccessToken, Scope, Session},
+    track::{Track, TrackPage, TrackQuery, TrackUpdate, TrashedTrack},
+    user::{Credentials, NewUser, Role, User},
+};
+
+/// Header requests authenticated by the api_token cookie need to change anything
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Cookie login sets next to the HttpOnly api_token cookie, holding the value of CSRF_HEADER
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Patch,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+        }
+    }
+
+    // Gets and deletes send their request as query, the others as json body
+    pub fn has_body(&self) -> bool {
+        !matches!(self, Method::Get | Method::Delete)
+    }
+}
+
+/// An endpoint of the server, values hold the parameters of its path
+pub trait Endpoint {
+    const METHOD: Method;
+    // Route as the server matches it, `:id` stands for the id of the value
+    const ROUTE: &'static str;
+    // The route of the endpoint in the API before /v1. It is served for one more release, so
+    // existing scripts keep working, and its responses carry a Deprecation header.
+    const DEPRECATED_ROUTE: Option<(Method, &'static str)> = None;
+    // Scope access tokens need, which the deprecated route shares. None if the endpoint is
+    // served without a credential.
+    const SCOPE: Option<Scope>;
+    // Sent as query or json body, see Method::has_body
+    type Request: Serialize + DeserializeOwned;
+    // Json, except for endpoints responding with a File
+    type Response;
+
+    // Path a request to this endpoint is sent to
+    fn path(&self) -> String;
+}
+
+/// Response of endpoints that send the bytes of a file instead of json
+#[derive(Debug, Clone, Copy)]
+pub struct File;
+
+#[cfg(feature = "openapi")]
+impl schemars::JsonSchema for File {
+    fn schema_name() -> String {
+        "File".to_string()
+    }
+
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            format: Some("binary".to_string()),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// Form the browser posts to download tracks, so it can save the response as a file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct DownloadForm {
+    // Comma separated, as form fields are plain strings. The deprecated route was posted the
+    // ids in brackets, which are still accepted.
+    pub ids: String,
+}
+
+impl DownloadForm {
+    pub fn new(ids: &[u32]) -> Self {
+        Self {
+            ids: ids.iter().join(","),
+        }
+    }
+
+    pub fn ids(&self) -> Result<Vec<u32>, ParseIntError> {
+        let ids = self.ids.trim();
+        let ids = ids
+            .strip_prefix('[')
+            .and_then(|ids| ids.strip_suffix(']'))
+            .unwrap_or(ids);
+        ids.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+/// Query of a signed download url, which downloads the tracks without a credential until it
+/// expires
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct SignedDownload {
+    // Comma separated, like the ids of DownloadForm
+    pub ids: String,
+    // Unix timestamp in seconds
+    pub expires: i64,
+    // Hex HMAC-SHA256 of ids and expires, only the server knows the key
+    pub signature: String,
+}
+
+// Declares an endpoint struct, endpoints with an id in their route are tuple structs of the id.
+// The scope is given as `scope <Scope variant>`, or as `public` for endpoints served without a
+// credential.
+macro_rules! endpoint {
+    (@deprecated) => {
+        None
+    };
+    (@deprecated $method:ident $route:literal) => {
+        Some((Method::$method, $route))
+    };
+    (@scope public) => {
+        None
+    };
+    (@scope scope $scope:ident) => {
+        Some(Scope::$scope)
+    };
+    (
+        $(#[$meta:meta])* $name:ident, $method:ident $route:literal, $request:ty => $response:ty,
+        $access:ident $($scope:ident)?
+        $(, deprecated $old_method:ident $old_route:literal)?
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name;
+
+        impl Endpoint for $name {
+            const METHOD: Method = Method::$method;
+            const ROUTE: &'static str = $route;
+            const SCOPE: Option<Scope> = endpoint!(@scope $access $($scope)?);
+            const DEPRECATED_ROUTE: Option<(Method, &'static str)> =
+                endpoint!(@deprecated $($old_method $old_route)?);
+            type Request = $request;
+            type Response = $response;
+
+            fn path(&self) -> String {
+                Self::ROUTE.to_string()
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])* $name:ident(id), $method:ident $route:literal, $request:ty => $response:ty,
+        $access:ident $($scope:ident)?
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name(pub u32);
+
+        impl Endpoint for $name {
+            const METHOD: Method = Method::$method;
+            const ROUTE: &'static str = $route;
+            const SCOPE: Option<Scope> = endpoint!(@scope $access $($scope)?);
+            type Request = $request;
+            type Response = $response;
+
+            fn path(&self) -> String {
+                Self::ROUTE.replace(":id", &self.0.to_string())
+            }
+        }
+    };
+}
+
+endpoint!(
+    /// Logs in, the session is set as HttpOnly api_token cookie and never responded with
+    Login, Post "/v1/sessions", Credentials => (), public,
+    deprecated Post "/use_secret"
+);
+endpoint!(
+    /// Revokes the session of the request
+    Logout, Delete "/v1/sessions/current", () => (), scope Admin
+);
+endpoint!(GetSessions, Get "/v1/sessions", () => Vec<Session>, scope Admin);
+endpoint!(RevokeSession(id), Delete "/v1/sessions/:id", () => (), scope Admin);
+endpoint!(GetAccessTokens, Get "/v1/access-tokens", () => Vec<AccessToken>, scope Admin);
+endpoint!(
+    /// The secret of the token is only part of this response
+    CreateAccessToken, Post "/v1/access-tokens", NewAccessToken => CreatedAccessToken, scope Admin
+);
+endpoint!(RevokeAccessToken(id), Delete "/v1/access-tokens/:id", () => (), scope Admin);
+
+endpoint!(
+    GetAllTracks, Get "/v1/tracks", () => Vec<Track>, scope ReadLibrary,
+    deprecated Get "/get_all_tracks"
+);
+endpoint!(QueryTracks, Get "/v1/tracks/search", TrackQuery => TrackPage, scope ReadLibrary);
+endpoint!(
+    /// Responds with the file of a single track, or a zip of several
+    DownloadTracks, Post "/v1/tracks/download", DownloadForm => File, scope Download,
+    deprecated Post "/download_tracks"
+);
+endpoint!(
+    /// Signs a download of the tracks, so the browser can download them without a credential.
+    /// The url expires after a few minutes, and when the server restarts.
+    CreateDownloadUrl, Post "/v1/download-urls", Vec<u32> => SignedDownload, scope Download
+);
+endpoint!(
+    /// Responds like DownloadTracks, authorized by the signature instead of a credential
+    DownloadSignedTracks, Get "/v1/downloads", SignedDownload => File, public
+);
+endpoint!(StreamTrack(id), Get "/v1/tracks/:id/stream", () => File, scope ReadLibrary);
+endpoint!(
+    /// Changes title and artists of the track, its tags and its file name
+    UpdateTrack(id), Patch "/v1/tracks/:id", TrackUpdate => Track, scope Admin
+);
+
+endpoint!(
+    /// Moves the tracks into the trash
+    DeleteTracks, Post "/v1/trash", Vec<u32> => (), scope Admin
+);
+endpoint!(GetTrash, Get "/v1/trash", () => Vec<TrashedTrack>, scope ReadLibrary);
+endpoint!(RestoreTracks, Post "/v1/trash/restore", Vec<u32> => Vec<Track>, scope Admin);
+endpoint!(
+    /// Deletes trashed tracks for good
+    PurgeTracks, Post "/v1/trash/purge", Vec<u32> => (), scope Admin
+);
+
+endpoint!(
+    /// Responds with the id of the queued archive job
+    ArchiveTrack, Post "/v1/archive-jobs", Candidate => u32, scope Archive,
+    deprecated Post "/archive_track"
+);
+endpoint!(
+    /// Queues an archive job for every track of a playlist or channel
+    ArchiveCollection, Post "/v1/archive-jobs/collection", CollectionCandidate => CollectionImport,
+    scope Archive
+);
+endpoint!(GetArchiveJobs, Get "/v1/archive-jobs", () => Vec<ArchiveJob>, scope ReadLibrary);
+endpoint!(GetArchiveJob(id), Get "/v1/archive-jobs/:id", () => ArchiveJob, scope ReadLibrary);
+
+endpoint!(GetPlaylists, Get "/v1/playlists", () => Vec<Playlist>, scope ReadLibrary);
+endpoint!(
+    /// Takes the name, responds with the id of the playlist
+    CreatePlaylist, Post "/v1/playlists", String => u32, scope Admin
+);
+endpoint!(
+    /// Takes the new name
+    RenamePlaylist(id), Put "/v1/playlists/:id/name", String => (), scope Admin
+);
+endpoint!(DeletePlaylist(id), Delete "/v1/playlists/:id", () => (), scope Admin);
+endpoint!(
+    /// Takes the ids of all playlists in their new order
+    OrderPlaylists, Put "/v1/playlists/order", Vec<u32> => (), scope Admin
+);
+endpoint!(AddPlaylistTracks(id), Post "/v1/playlists/:id/tracks", Vec<u32> => (), scope Admin);
+endpoint!(
+    RemovePlaylistTracks(id), Post "/v1/playlists/:id/tracks/remove", Vec<u32> => (), scope Admin
+);
+endpoint!(
+    /// Takes the ids of all tracks of the playlist in their new order
+    OrderPlaylistTracks(id), Put "/v1/playlists/:id/tracks/order", Vec<u32> => (), scope Admin
+);
+
+endpoint!(GetCurrentUser, Get "/v1/users/me", () => User, scope ReadLibrary);
+endpoint!(GetUsers, Get "/v1/users", () => Vec<User>, scope Admin);
+endpoint!(CreateUser, Post "/v1/users", NewUser => User, scope Admin);
+endpoint!(SetUserRole(id), Put "/v1/users/:id/role", Role => (), scope Admin);
+endpoint!(
+    /// Takes the new password
+    SetUserPassword(id), Put "/v1/users/:id/password", String => (), scope Admin
+);
+endpoint!(DeleteUser(id), Delete "/v1/users/:id", () => (), scope Admin);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_forms_accept_the_deprecated_brackets() {
+        let form = |ids: &str| DownloadForm {
+            ids: ids.to_string(),
+        };
+        assert_eq!(form("1,2").ids(), Ok(vec![1, 2]));
+        assert_eq!(form("[1,2]").ids(), Ok(vec![1, 2]));
+        assert_eq!(form("[]").ids(), Ok(vec![]));
+        assert!(form("[1,2").ids().is_err());
+    }
+}
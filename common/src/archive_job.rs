@@ -0,0 +1,66 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::candidate::Candidate;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub enum ArchiveJobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl ArchiveJobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArchiveJobStatus::Queued => "queued",
+            ArchiveJobStatus::Running => "running",
+            ArchiveJobStatus::Succeeded => "succeeded",
+            ArchiveJobStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "queued" => Some(ArchiveJobStatus::Queued),
+            "running" => Some(ArchiveJobStatus::Running),
+            "succeeded" => Some(ArchiveJobStatus::Succeeded),
+            "failed" => Some(ArchiveJobStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct ArchiveJob {
+    pub id: u32,
+    pub candidate: Candidate,
+    pub status: ArchiveJobStatus,
+    // Reason the job failed, only set if status is Failed
+    pub error: Option<String>,
+    // Id of the archived track, only set if status is Succeeded
+    pub track_id: Option<u32>,
+    // Playlist the track is added to once it is archived
+    #[serde(default)]
+    pub playlist_id: Option<u32>,
+    // Position of the track in the collection the playlist was imported from
+    #[serde(default)]
+    pub playlist_position: Option<u32>,
+    pub date_created: NaiveDateTime,
+    pub date_updated: NaiveDateTime,
+}
+
+/// Outcome of queueing every track of a collection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct CollectionImport {
+    pub job_ids: Vec<u32>,
+    // Tracks which were not queued because they are already archived
+    pub already_archived: u32,
+    // Entries of the collection no source provider recognized
+    pub unsupported: u32,
+    pub playlist_id: Option<u32>,
+}
@@ -1,7 +1,10 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
+use crate::source::{Collection, Source, SourceKind};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
 pub struct Candidate {
     pub url: String,
     pub title: Option<String>,
@@ -11,50 +14,32 @@ pub struct Candidate {
 impl Candidate {
     // Validates self if possible
     pub fn validated(mut self) -> anyhow::Result<Self> {
-        self.url = normalize_and_validate_url(&self.url).context("Url is invalid")?;
+        self.url = Source::recognize(&self.url).context("Url is invalid")?.url;
         Ok(self)
     }
-}
 
-fn normalize_and_validate_url(url: &str) -> Option<String> {
-    let url = url
-        .trim()
-        .trim_start_matches("https://")
-        .trim_start_matches("http://")
-        .trim_start_matches("www.");
-
-    // YOUTUBE LINKS
-    // Check youtu.be links
-    if url.starts_with("youtu.be/") {
-        let id = url
-            .trim_start_matches("youtu.be/")
-            .split("?")
-            .next()?
-            .to_string();
-        return Some(format!("youtu.be/{}", id));
+    // Returns the kind of source the url belongs to
+    pub fn source(&self) -> Option<SourceKind> {
+        Source::recognize(&self.url).map(|source| source.kind)
     }
+}
 
-    // Check youtube.com links
-    if url.starts_with("youtube.com/watch?v=") {
-        let id = url
-            .trim_start_matches("youtube.com/watch?v=")
-            .split("&")
-            .next()?
-            .to_string();
-        return Some(format!("youtu.be/{}", id));
-    }
-
-    // SOUNDCLOUD LINKS
-    // Check soundcloud.com
-    /*if url.starts_with("soundcloud.com/") {
-        let mut splits = url.trim_start_matches("soundcloud.com/").split("/");
-        let artist = splits.next()?.to_string();
-        let id = splits.next()?.split("?").next()?.to_string();
-        return Some(format!("soundcloud.com/{}/{}", artist, id));
-    }*/
-
-    // Check on.soundcloud.com
-    // WE DO NOT SUPPORT THIS CURRENTLY
+/// A playlist or channel whose tracks should all be archived
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct CollectionCandidate {
+    pub url: String,
+    // Whether to create a playlist named like the collection holding all of its tracks
+    #[serde(default)]
+    pub create_playlist: bool,
+}
 
-    None
+impl CollectionCandidate {
+    // Validates self if possible
+    pub fn validated(mut self) -> anyhow::Result<Self> {
+        self.url = Collection::recognize(&self.url)
+            .context("Url is not a playlist or channel")?
+            .url;
+        Ok(self)
+    }
 }
@@ -0,0 +1,166 @@
+//! Typed client of the server, requests and responses are those of the endpoints in api
+
+use std::fmt;
+
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Client, RequestBuilder, Response,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    api::{Endpoint, File, Method, CSRF_HEADER},
+    error::{ApiError, ErrorKind, RequestError},
+};
+
+/// Sends requests to the endpoints of a server, clones share their connections
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    http: Client,
+    base_url: String,
+    credential: Option<Credential>,
+}
+
+/// What requests are authenticated with
+#[derive(Clone)]
+pub enum Credential {
+    // The CSRF token of a login. The session itself is the HttpOnly api_token cookie, which only
+    // browsers attach to requests of the client.
+    Session(String),
+    // The secret of a personal access token
+    AccessToken(String),
+}
+
+// Credentials grant access, so they must never end up in logs
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::Session(_) => f.write_str("Session(<redacted>)"),
+            Credential::AccessToken(_) => f.write_str("AccessToken(<redacted>)"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for RequestError {
+    fn from(e: reqwest::Error) -> Self {
+        RequestError::Transport(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for RequestError {
+    fn from(e: serde_json::Error) -> Self {
+        RequestError::Transport(e.to_string())
+    }
+}
+
+impl ApiClient {
+    // base_url is where the routes of the server are served from, e.g. https://host/api
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            http: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            credential: None,
+        }
+    }
+
+    pub fn with_credential(mut self, credential: Option<Credential>) -> Self {
+        self.credential = credential;
+        self
+    }
+
+    // Url of the endpoint, e.g. for elements the browser loads on its own
+    pub fn url(&self, endpoint: &impl Endpoint) -> String {
+        format!("{}{}", self.base_url, endpoint.path())
+    }
+
+    // Url of a get including its request, e.g. for downloads the browser saves on its own
+    pub fn query_url<E: Endpoint>(
+        &self,
+        endpoint: &E,
+        request: &E::Request,
+    ) -> Result<String, RequestError> {
+        let request = self.http.get(self.url(endpoint)).query(request).build()?;
+        Ok(request.url().to_string())
+    }
+
+    pub async fn call<E>(
+        &self,
+        endpoint: E,
+        request: &E::Request,
+    ) -> Result<E::Response, RequestError>
+    where
+        E: Endpoint,
+        E::Response: DeserializeOwned,
+    {
+        let builder = self
+            .http
+            .request(http_method(E::METHOD), self.url(&endpoint));
+        let builder = if E::METHOD.has_body() {
+            builder
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(request)?)
+        } else {
+            builder.query(request)
+        };
+        let bytes = self.send(builder).await?.bytes().await?;
+        // Endpoints without a response send an empty body
+        if bytes.is_empty() {
+            return Ok(serde_json::from_slice(b"null")?);
+        }
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    // Requests to file endpoints are posted as forms, which browsers can post as well
+    pub async fn download<E>(
+        &self,
+        endpoint: E,
+        request: &E::Request,
+    ) -> Result<Vec<u8>, RequestError>
+    where
+        E: Endpoint<Response = File>,
+    {
+        let builder = self
+            .http
+            .request(http_method(E::METHOD), self.url(&endpoint));
+        let builder = if E::METHOD.has_body() {
+            builder.form(request)
+        } else {
+            builder.query(request)
+        };
+        Ok(self.send(builder).await?.bytes().await?.to_vec())
+    }
+
+    async fn send(&self, builder: RequestBuilder) -> Result<Response, RequestError> {
+        let builder = match &self.credential {
+            Some(Credential::Session(csrf_token)) => builder.header(CSRF_HEADER, csrf_token),
+            Some(Credential::AccessToken(secret)) => {
+                builder.header(AUTHORIZATION, format!("Bearer {secret}"))
+            }
+            None => builder,
+        };
+        error_for_status(builder.send().await?).await
+    }
+}
+
+fn http_method(method: Method) -> reqwest::Method {
+    match method {
+        Method::Get => reqwest::Method::GET,
+        Method::Post => reqwest::Method::POST,
+        Method::Put => reqwest::Method::PUT,
+        Method::Patch => reqwest::Method::PATCH,
+        Method::Delete => reqwest::Method::DELETE,
+    }
+}
+
+// Decodes the ApiError of error responses, responses without one are classified by their status
+async fn error_for_status(response: Response) -> Result<Response, RequestError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let bytes = response.bytes().await?;
+    let error = serde_json::from_slice(&bytes).unwrap_or_else(|_| {
+        ApiError::new(ErrorKind::from_status(status.as_u16()), status.to_string())
+    });
+    Err(RequestError::Api(error))
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Body of every error response of the server
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct ApiError {
+    pub kind: ErrorKind,
+    // Meant to be shown to the user
+    pub message: String,
+}
+
+// What went wrong, each kind has its own HTTP status code
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    // The request is malformed or invalid
+    BadRequest,
+    // No or an invalid or expired token was sent
+    Unauthorized,
+    // The user or access token lacks the permission
+    Forbidden,
+    NotFound,
+    // The request contradicts the current state, e.g. a name that is already taken
+    Conflict,
+    TooManyRequests,
+    // A site tracks are archived from failed
+    BadGateway,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> u16 {
+        match self {
+            ErrorKind::BadRequest => 400,
+            ErrorKind::Unauthorized => 401,
+            ErrorKind::Forbidden => 403,
+            ErrorKind::NotFound => 404,
+            ErrorKind::Conflict => 409,
+            ErrorKind::TooManyRequests => 429,
+            ErrorKind::BadGateway => 502,
+            ErrorKind::Internal => 500,
+        }
+    }
+
+    // Responses without an ApiError body, e.g. from a proxy, are classified by their status
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            401 => ErrorKind::Unauthorized,
+            403 => ErrorKind::Forbidden,
+            404 => ErrorKind::NotFound,
+            409 => ErrorKind::Conflict,
+            429 => ErrorKind::TooManyRequests,
+            502 => ErrorKind::BadGateway,
+            status if (400..500).contains(&status) => ErrorKind::BadRequest,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+impl ApiError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Why a request of a client failed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestError {
+    // The server answered with an error
+    Api(ApiError),
+    // The server could not be reached or its answer could not be read
+    Transport(String),
+}
+
+impl RequestError {
+    pub fn kind(&self) -> Option<ErrorKind> {
+        match self {
+            RequestError::Api(e) => Some(e.kind),
+            RequestError::Transport(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Api(e) => write!(f, "{e}"),
+            RequestError::Transport(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
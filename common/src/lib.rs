@@ -1,6 +1,14 @@
 #![feature(is_sorted)]
 #![feature(iter_intersperse)]
 
+pub mod api;
+pub mod archive_job;
 pub mod candidate;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod error;
+pub mod playlist;
+pub mod source;
 pub mod token;
 pub mod track;
+pub mod user;
@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct Playlist {
+    pub id: u32,
+    pub name: String,
+    // Ids of the tracks in playback order, a track may appear more than once
+    pub track_ids: Vec<u32>,
+}
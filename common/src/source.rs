@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub enum SourceKind {
+    // Tracks archived before sources were recorded are all from YouTube
+    #[default]
+    #[serde(rename = "youtube")]
+    YouTube,
+    #[serde(rename = "soundcloud")]
+    SoundCloud,
+}
+
+impl SourceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceKind::YouTube => "youtube",
+            SourceKind::SoundCloud => "soundcloud",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "youtube" => Some(SourceKind::YouTube),
+            "soundcloud" => Some(SourceKind::SoundCloud),
+            _ => None,
+        }
+    }
+}
+
+/// A website tracks can be archived from
+pub trait SourceProvider: Sync {
+    fn kind(&self) -> SourceKind;
+
+    // Returns the canonical id of the track the url points to, None if the url is not
+    // a track of this provider. The url has its scheme and "www." already removed.
+    fn canonical_id(&self, url: &str) -> Option<String>;
+
+    // Builds the normalized url, which is stored in the database, from a canonical id
+    fn normalized_url(&self, id: &str) -> String;
+
+    // Returns the normalized url of the playlist or channel the url points to, None if the
+    // url is not a collection of this provider. Gets the same input as canonical_id.
+    fn collection_url(&self, _url: &str) -> Option<String> {
+        None
+    }
+}
+
+pub static PROVIDERS: &[&dyn SourceProvider] = &[&YouTube, &SoundCloud];
+
+/// A recognized track url
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub kind: SourceKind,
+    pub id: String,
+    pub url: String,
+}
+
+impl Source {
+    // Finds the provider of the url and normalizes it
+    pub fn recognize(url: &str) -> Option<Self> {
+        let url = strip_scheme(url);
+        PROVIDERS.iter().find_map(|provider| {
+            let id = provider.canonical_id(url)?;
+            Some(Source {
+                kind: provider.kind(),
+                url: provider.normalized_url(&id),
+                id,
+            })
+        })
+    }
+}
+
+/// A recognized playlist or channel url, which expands into many tracks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collection {
+    pub kind: SourceKind,
+    pub url: String,
+}
+
+impl Collection {
+    // Finds the provider of the url and normalizes it
+    pub fn recognize(url: &str) -> Option<Self> {
+        let url = strip_scheme(url);
+        PROVIDERS.iter().find_map(|provider| {
+            Some(Collection {
+                kind: provider.kind(),
+                url: provider.collection_url(url)?,
+            })
+        })
+    }
+}
+
+fn strip_scheme(url: &str) -> &str {
+    url.trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.")
+}
+
+pub struct YouTube;
+
+impl YouTube {
+    // Returns the path after any of the youtube.com hosts
+    fn strip_host(url: &str) -> Option<&str> {
+        url.strip_prefix("m.")
+            .or_else(|| url.strip_prefix("music."))
+            .unwrap_or(url)
+            .strip_prefix("youtube.com/")
+    }
+
+    fn valid_id(id: &str) -> bool {
+        id.len() == 11
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+}
+
+impl SourceProvider for YouTube {
+    fn kind(&self) -> SourceKind {
+        SourceKind::YouTube
+    }
+
+    fn canonical_id(&self, url: &str) -> Option<String> {
+        let id = if let Some(rest) = url.strip_prefix("youtu.be/") {
+            rest.split(['?', '#', '/']).next()?
+        } else {
+            let rest = Self::strip_host(url)?;
+            if let Some(query) = rest.strip_prefix("watch?") {
+                // The video id may be any of the query parameters
+                query
+                    .split('#')
+                    .next()?
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("v="))?
+            } else if let Some(rest) = rest
+                .strip_prefix("shorts/")
+                .or_else(|| rest.strip_prefix("embed/"))
+            {
+                rest.split(['?', '#', '/']).next()?
+            } else {
+                return None;
+            }
+        };
+
+        if !Self::valid_id(id) {
+            return None;
+        }
+        Some(id.to_string())
+    }
+
+    fn normalized_url(&self, id: &str) -> String {
+        format!("youtu.be/{}", id)
+    }
+
+    fn collection_url(&self, url: &str) -> Option<String> {
+        let rest = Self::strip_host(url)?;
+        if let Some(query) = rest.strip_prefix("playlist?") {
+            let list = query
+                .split('#')
+                .next()?
+                .split('&')
+                .find_map(|param| param.strip_prefix("list="))?;
+            if list.is_empty() {
+                return None;
+            }
+            return Some(format!("youtube.com/playlist?list={}", list));
+        }
+
+        // Channels are listed by their uploads, not their home page
+        let path = rest.split(['?', '#']).next()?;
+        let mut segments = path.split('/');
+        let channel = match segments.next()? {
+            handle if handle.len() > 1 && handle.starts_with('@') => handle.to_string(),
+            prefix @ ("channel" | "c" | "user") => {
+                let name = segments.next().filter(|name| !name.is_empty())?;
+                format!("{}/{}", prefix, name)
+            }
+            _ => return None,
+        };
+        Some(format!("youtube.com/{}/videos", channel))
+    }
+}
+
+pub struct SoundCloud;
+
+impl SoundCloud {
+    // Top level paths which are not artists
+    const NOT_ARTISTS: &'static [&'static str] = &[
+        "discover", "search", "stream", "upload", "you", "charts", "pages", "settings",
+    ];
+
+    // Returns the path segments after the soundcloud.com host
+    fn segments(url: &str) -> Option<Vec<&str>> {
+        let rest = url
+            .strip_prefix("m.")
+            .unwrap_or(url)
+            .strip_prefix("soundcloud.com/")?;
+        let path = rest.split(['?', '#']).next()?.trim_end_matches('/');
+        Some(path.split('/').collect())
+    }
+
+    // Paths below an artist which are not tracks
+    const RESERVED: &'static [&'static str] = &[
+        "sets",
+        "likes",
+        "tracks",
+        "albums",
+        "reposts",
+        "popular-tracks",
+        "followers",
+        "following",
+        "comments",
+    ];
+}
+
+impl SourceProvider for SoundCloud {
+    fn kind(&self) -> SourceKind {
+        SourceKind::SoundCloud
+    }
+
+    fn canonical_id(&self, url: &str) -> Option<String> {
+        // on.soundcloud.com short links can not be resolved without a request,
+        // so they are not supported
+        let [artist, track] = Self::segments(url)?[..] else {
+            return None;
+        };
+        if artist.is_empty() || track.is_empty() || Self::RESERVED.contains(&track) {
+            return None;
+        }
+        Some(format!("{}/{}", artist, track).to_lowercase())
+    }
+
+    fn normalized_url(&self, id: &str) -> String {
+        format!("soundcloud.com/{}", id)
+    }
+
+    fn collection_url(&self, url: &str) -> Option<String> {
+        let path = match Self::segments(url)?[..] {
+            [artist, "sets", set] if !artist.is_empty() && !set.is_empty() => {
+                format!("{}/sets/{}", artist, set)
+            }
+            // Artists are listed by their uploads
+            [artist] | [artist, "tracks"]
+                if !artist.is_empty() && !Self::NOT_ARTISTS.contains(&artist) =>
+            {
+                format!("{}/tracks", artist)
+            }
+            _ => return None,
+        };
+        Some(format!("soundcloud.com/{}", path.to_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(url: &str) -> Option<(SourceKind, String)> {
+        Source::recognize(url).map(|source| (source.kind, source.url))
+    }
+
+    fn collection(url: &str) -> Option<String> {
+        Collection::recognize(url).map(|collection| collection.url)
+    }
+
+    #[test]
+    fn youtube_tracks_are_normalized() {
+        let expected = Some((SourceKind::YouTube, "youtu.be/dQw4w9WgXcQ".to_string()));
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "http://youtube.com/watch?feature=share&v=dQw4w9WgXcQ#t=10",
+            "m.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123",
+            "https://music.youtube.com/watch?v=dQw4w9WgXcQ",
+            "youtu.be/dQw4w9WgXcQ?si=abc",
+            "  https://youtube.com/shorts/dQw4w9WgXcQ  ",
+            "youtube.com/embed/dQw4w9WgXcQ/",
+        ] {
+            assert_eq!(track(url), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn invalid_youtube_tracks_are_rejected() {
+        for url in [
+            "youtube.com/watch?v=short",
+            "youtube.com/watch?list=PL123",
+            "youtu.be/dQw4w9WgXc!",
+            "youtube.com/@artist",
+            "notyoutube.com/watch?v=dQw4w9WgXcQ",
+        ] {
+            assert_eq!(track(url), None, "{url}");
+        }
+    }
+
+    #[test]
+    fn soundcloud_tracks_are_normalized() {
+        assert_eq!(
+            track("https://m.soundcloud.com/Artist/Some-Track/?in=x#y"),
+            Some((
+                SourceKind::SoundCloud,
+                "soundcloud.com/artist/some-track".to_string()
+            ))
+        );
+        for url in [
+            "soundcloud.com/artist",
+            "soundcloud.com/artist/sets",
+            "soundcloud.com/artist/likes",
+            "soundcloud.com/artist/sets/album",
+            "on.soundcloud.com/abc123",
+        ] {
+            assert_eq!(track(url), None, "{url}");
+        }
+    }
+
+    #[test]
+    fn collections_are_normalized() {
+        for (url, expected) in [
+            (
+                "https://www.youtube.com/playlist?list=PL123&index=2",
+                Some("youtube.com/playlist?list=PL123"),
+            ),
+            ("youtube.com/playlist?list=", None),
+            (
+                "youtube.com/@artist/featured",
+                Some("youtube.com/@artist/videos"),
+            ),
+            (
+                "youtube.com/channel/UC123",
+                Some("youtube.com/channel/UC123/videos"),
+            ),
+            ("youtube.com/c/", None),
+            ("youtube.com/watch?v=dQw4w9WgXcQ", None),
+            (
+                "soundcloud.com/Artist/sets/Album",
+                Some("soundcloud.com/artist/sets/album"),
+            ),
+            (
+                "soundcloud.com/artist/",
+                Some("soundcloud.com/artist/tracks"),
+            ),
+            (
+                "soundcloud.com/artist/tracks",
+                Some("soundcloud.com/artist/tracks"),
+            ),
+            ("soundcloud.com/discover", None),
+            ("soundcloud.com/artist/some-track", None),
+        ] {
+            assert_eq!(collection(url).as_deref(), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn kinds_round_trip() {
+        for kind in [SourceKind::YouTube, SourceKind::SoundCloud] {
+            assert_eq!(SourceKind::parse(kind.as_str()), Some(kind));
+        }
+        assert_eq!(SourceKind::parse("bandcamp"), None);
+    }
+}
@@ -1,9 +1,20 @@
+use std::fmt;
+
+use chrono::NaiveDateTime;
 use random_string::generate;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
 pub struct ApiToken(String);
 
+// Tokens grant access, so they must never end up in logs
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiToken(<redacted>)")
+    }
+}
+
 impl ApiToken {
     pub fn new() -> Self {
         let charset = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
@@ -18,3 +29,111 @@ impl ApiToken {
         &self.0
     }
 }
+
+/// A login of a user, identified by the ApiToken handed out for it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct Session {
+    pub id: u32,
+    pub user_id: u32,
+    pub date_created: NaiveDateTime,
+    pub date_last_seen: NaiveDateTime,
+}
+
+/// What a personal access token may be used for
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    // Browsing and streaming the library, playlists and archive jobs
+    ReadLibrary,
+    // Downloading track files
+    Download,
+    // Queueing archive jobs
+    Archive,
+    // Everything else, including edits and managing users, sessions and tokens
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadLibrary => "read_library",
+            Scope::Download => "download",
+            Scope::Archive => "archive",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read_library" => Some(Scope::ReadLibrary),
+            "download" => Some(Scope::Download),
+            "archive" => Some(Scope::Archive),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// A named, long lived token for scripts, sent as `Authorization: Bearer <secret>`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct AccessToken {
+    pub id: u32,
+    pub user_id: u32,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub date_created: NaiveDateTime,
+    // The token never expires if None
+    pub date_expires: Option<NaiveDateTime>,
+    pub date_last_used: Option<NaiveDateTime>,
+}
+
+impl AccessToken {
+    // Prefix of all secrets, so they are recognizable when leaked
+    pub const PREFIX: &'static str = "hpat_";
+
+    pub fn new_secret() -> String {
+        let charset = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+        format!("{}{}", Self::PREFIX, generate(40, charset))
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// Request to create an AccessToken for the current user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct NewAccessToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub date_expires: Option<NaiveDateTime>,
+}
+
+impl NewAccessToken {
+    // Trims the name and checks that name and scopes are given
+    pub fn validated(mut self) -> anyhow::Result<Self> {
+        self.name = self.name.trim().to_string();
+        if self.name.is_empty() {
+            anyhow::bail!("Name must not be empty");
+        }
+        if self.scopes.is_empty() {
+            anyhow::bail!("At least one scope is required");
+        }
+        self.scopes.sort_by_key(|scope| scope.as_str());
+        self.scopes.dedup();
+        Ok(self)
+    }
+}
+
+/// A newly created AccessToken together with its secret, which is only shown this once
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct CreatedAccessToken {
+    pub token: AccessToken,
+    pub secret: String,
+}
@@ -1,11 +1,16 @@
-use chrono::NaiveDate;
+use chrono::{NaiveDate, NaiveDateTime};
 use derive_getters::Getters;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Getters)]
+use crate::source::SourceKind;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Getters)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
 pub struct Track {
     pub id: u32,
     pub url: String,
+    #[serde(default)]
+    pub source: SourceKind,
     pub title: String,
     pub artists: Vec<String>,
     pub date_archived: NaiveDate,
@@ -15,6 +20,7 @@ impl Track {
     pub fn new(
         id: u32,
         url: String,
+        source: SourceKind,
         title: String,
         artists: Vec<String>,
         date_archived: NaiveDate,
@@ -22,6 +28,7 @@ impl Track {
         Self {
             id,
             url,
+            source,
             title,
             artists,
             date_archived,
@@ -48,3 +55,105 @@ impl Track {
         filenamify::filenamify(result)
     }
 }
+
+/// A deleted track waiting in the trash to be restored or purged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct TrashedTrack {
+    pub track: Track,
+    pub date_trashed: NaiveDateTime,
+}
+
+/// New metadata for an archived track
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct TrackUpdate {
+    pub title: String,
+    pub artists: Vec<String>,
+}
+
+impl TrackUpdate {
+    // Trims all fields and checks that title and artists are given
+    pub fn validated(mut self) -> anyhow::Result<Self> {
+        self.title = self.title.trim().to_string();
+        self.artists = self
+            .artists
+            .iter()
+            .map(|a| a.trim().to_string())
+            .filter(|a| !a.is_empty())
+            .collect();
+        if self.title.is_empty() {
+            anyhow::bail!("Title must not be empty");
+        }
+        if self.artists.is_empty() {
+            anyhow::bail!("At least one artist is required");
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum TrackSortField {
+    #[default]
+    Title,
+    FirstArtist,
+    DateArchived,
+    Url,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// Filters, sorts and pages the track library
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct TrackQuery {
+    // Matched against title, artists and url, empty matches everything
+    #[serde(default)]
+    pub query: String,
+    #[serde(default)]
+    pub sort: TrackSortField,
+    #[serde(default)]
+    pub direction: SortDirection,
+    #[serde(default = "TrackQuery::default_limit")]
+    pub limit: u32,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+impl TrackQuery {
+    pub const MAX_LIMIT: u32 = 500;
+
+    fn default_limit() -> u32 {
+        50
+    }
+}
+
+impl Default for TrackQuery {
+    fn default() -> Self {
+        Self {
+            query: String::new(),
+            sort: TrackSortField::default(),
+            direction: SortDirection::default(),
+            limit: Self::default_limit(),
+            offset: 0,
+        }
+    }
+}
+
+/// One page of a TrackQuery result
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct TrackPage {
+    pub tracks: Vec<Track>,
+    // Number of tracks matching the query over all pages
+    pub total: u32,
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// Roles are ordered by how much they permit, every role can do what the roles below it can
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // May browse, stream and download the library
+    Listener,
+    // May additionally archive, edit and trash tracks and edit playlists
+    Contributor,
+    // May additionally purge the trash and manage users
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Listener => "listener",
+            Role::Contributor => "contributor",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "listener" => Some(Role::Listener),
+            "contributor" => Some(Role::Contributor),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct User {
+    pub id: u32,
+    pub name: String,
+    pub role: Role,
+}
+
+/// Name and password a user logs in with
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct Credentials {
+    pub name: String,
+    pub password: String,
+}
+
+// Passwords must never end up in logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("name", &self.name)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// An account to be created by an admin
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(schemars::JsonSchema))]
+pub struct NewUser {
+    pub name: String,
+    pub password: String,
+    pub role: Role,
+}
+
+impl fmt::Debug for NewUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewUser")
+            .field("name", &self.name)
+            .field("password", &"<redacted>")
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+impl NewUser {
+    pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+    // Trims the name and checks that name and password are usable
+    pub fn validated(mut self) -> anyhow::Result<Self> {
+        self.name = self.name.trim().to_string();
+        if self.name.is_empty() {
+            anyhow::bail!("Name must not be empty");
+        }
+        validate_password(&self.password)?;
+        Ok(self)
+    }
+}
+
+pub fn validate_password(password: &str) -> anyhow::Result<()> {
+    if password.chars().count() < NewUser::MIN_PASSWORD_LENGTH {
+        anyhow::bail!(
+            "Password must be at least {} characters long",
+            NewUser::MIN_PASSWORD_LENGTH
+        );
+    }
+    Ok(())
+}
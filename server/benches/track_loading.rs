@@ -0,0 +1,169 @@
+// Compares loading a large library with one artist query per track, as the server used to,
+// against the batched queries of Database. Run with
+// `cargo bench --bench track_loading [-- <track count>]`.
+
+// The server is a binary crate, so the modules are included directly. Their tests are not
+// built into the bench, which leaves their imports unused.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/database.rs"]
+mod database;
+#[allow(dead_code)]
+#[path = "../src/error.rs"]
+mod error;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/migrations.rs"]
+mod migrations;
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use chrono::NaiveDate;
+use common::track::Track;
+use database::Database;
+use rusqlite::Connection;
+use tempfile::TempDir;
+
+const DEFAULT_TRACK_COUNT: u32 = 50_000;
+const ARTIST_COUNT: u32 = 5_000;
+const RUNS: u32 = 5;
+
+fn main() {
+    let track_count = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_TRACK_COUNT);
+
+    // Removed when dropped, also if an assertion fails
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    create_fixture(dir, track_count);
+    println!("Fixture: {track_count} tracks, {ARTIST_COUNT} artists");
+
+    let con = Connection::open(dir.join("harmony.db3")).unwrap();
+    let mut db = Database::new(dir.to_path_buf()).unwrap();
+
+    let expected = per_track_queries(&con, None);
+    assert_eq!(db.all_tracks().unwrap(), expected);
+    report("all_tracks, one query per track", || {
+        per_track_queries(&con, None);
+    });
+    report("all_tracks, batched queries", || {
+        db.all_tracks().unwrap();
+    });
+
+    // A page of a track query
+    let page = (1..=track_count).step_by(97).take(100).collect::<Vec<_>>();
+    let expected = per_track_queries(&con, Some(&page));
+    assert_eq!(db.get_tracks(page.iter().copied()).unwrap(), expected);
+    report("get_tracks of 100, one query per track", || {
+        per_track_queries(&con, Some(&page));
+    });
+    report("get_tracks of 100, batched queries", || {
+        db.get_tracks(page.iter().copied()).unwrap();
+    });
+}
+
+// Writes the tracks in one transaction, inserting them one by one would take minutes
+fn create_fixture(dir: &Path, track_count: u32) {
+    // Creates the schema
+    drop(Database::new(PathBuf::from(dir)).unwrap());
+
+    let mut con = Connection::open(dir.join("harmony.db3")).unwrap();
+    let tx = con.transaction().unwrap();
+    {
+        let mut artist_stmt = tx
+            .prepare("INSERT INTO artists (id, name) VALUES (?1, ?2)")
+            .unwrap();
+        for id in 1..=ARTIST_COUNT {
+            artist_stmt.execute((id, format!("Artist {id}"))).unwrap();
+        }
+
+        let mut track_stmt = tx
+            .prepare(
+                "INSERT INTO tracks (id, url, title, date_archived, source)
+                VALUES (?1, ?2, ?3, ?4, 'youtube')",
+            )
+            .unwrap();
+        let mut track_artist_stmt = tx
+            .prepare("INSERT INTO track_artists (track_id, artist_id) VALUES (?1, ?2)")
+            .unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        for id in 1..=track_count {
+            track_stmt
+                .execute((
+                    id,
+                    format!("youtu.be/{id:011}"),
+                    format!("Track {id}"),
+                    date,
+                ))
+                .unwrap();
+            // One to three artists, in an order that differs from their ids
+            for n in 0..(id % 3 + 1) {
+                let artist_id = (id * 7 + (2 - n) * 1_013) % ARTIST_COUNT + 1;
+                track_artist_stmt.execute((id, artist_id)).unwrap();
+            }
+        }
+    }
+    tx.commit().unwrap();
+}
+
+// How tracks were loaded before: the tracks, then the artists of every track on their own
+fn per_track_queries(con: &Connection, ids: Option<&[u32]>) -> Vec<Track> {
+    let ids = match ids {
+        Some(ids) => ids.to_vec(),
+        None => con
+            .prepare("SELECT id FROM tracks ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|id| id.unwrap())
+            .collect(),
+    };
+    let mut track_sql = con
+        .prepare("SELECT id, url, title, date_archived FROM tracks WHERE id = ?1")
+        .unwrap();
+    let mut artist_sql = con
+        .prepare(
+            "SELECT artists.name FROM artists
+            JOIN track_artists ON artists.id = track_artists.artist_id
+            WHERE track_artists.track_id = ?1
+            ORDER BY track_artists.rowid",
+        )
+        .unwrap();
+    ids.into_iter()
+        .map(|id| {
+            let mut track = track_sql
+                .query_row([id], |row| {
+                    Ok(Track {
+                        id: row.get(0)?,
+                        url: row.get(1)?,
+                        source: Default::default(),
+                        title: row.get(2)?,
+                        artists: vec![],
+                        date_archived: row.get(3)?,
+                    })
+                })
+                .unwrap();
+            track.artists = artist_sql
+                .query_map([id], |row| row.get(0))
+                .unwrap()
+                .map(|name| name.unwrap())
+                .collect();
+            track
+        })
+        .collect()
+}
+
+fn report(name: &str, mut f: impl FnMut()) {
+    // Warm up the page cache
+    f();
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        f();
+        best = best.min(start.elapsed());
+    }
+    println!("{name:<45} {best:>12.2?}");
+}
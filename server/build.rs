@@ -0,0 +1,4 @@
+// The client is embedded from client/dist, so a new build of it has to rebuild the server
+fn main() {
+    println!("cargo:rerun-if-changed=../client/dist");
+}
@@ -1,7 +1,7 @@
 use std::{
-    iter::once,
+    path::Path,
     process::{Command, Stdio},
-    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use anyhow::{bail, Context};
@@ -15,101 +15,166 @@ use async_openai::{
 };
 use audiotags::Tag;
 use chrono::Utc;
-use common::{candidate::Candidate, track::Track};
+use common::{archive_job::ArchiveJobStatus, candidate::Candidate, track::Track};
 use crossbeam::channel::Receiver;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, warn};
 
-use crate::{database::Database, DOWNLOAD_DIR, TRACK_DIR};
+use crate::{config::config, error::ServerError, pool::DatabasePool, DOWNLOAD_DIR, TRACK_DIR};
 
-pub fn archiver_task(receiver: Receiver<Candidate>, db: Arc<Mutex<Database>>) {
-    let mut gpt_client = match std::env::var("OPENAI_API_KEY") {
-        Ok(_) => Some(Client::new()),
-        Err(_) => {
-            info!("OPENAI_API_KEY is not set: Not using OpenAI capabilities");
+pub fn archiver_task(receiver: Receiver<()>, db: DatabasePool) {
+    let llm = &config().llm;
+    let mut gpt_client = match &llm.api_key {
+        Some(api_key) => {
+            let mut openai_config = OpenAIConfig::new().with_api_key(api_key);
+            if let Some(api_base) = &llm.api_base {
+                openai_config = openai_config.with_api_base(api_base);
+            }
+            Some(Client::with_config(openai_config))
+        }
+        None => {
+            info!("No LLM API key is set: Not using OpenAI capabilities");
             None
         }
     };
 
+    // Jobs that were running when the server stopped are started again
+    if let Err(e) = db.write_blocking(|db| db.requeue_running_archive_jobs()) {
+        error!("Unable to requeue interrupted archive jobs: {e:#}");
+    }
+
     loop {
-        let mut candidate = match receiver.recv().unwrap().validated() {
-            Ok(candidate) => candidate,
+        let job = match db.write_blocking(|db| db.next_queued_archive_job()) {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                // Wait until a new job is queued
+                receiver.recv().unwrap();
+                continue;
+            }
             Err(e) => {
-                warn!("Received invalid candidate: {e}");
+                // Try again later instead of failing every job in a row
+                error!("Unable to read the next archive job: {e:#}");
+                std::thread::sleep(Duration::from_secs(60));
                 continue;
             }
         };
 
-        debug!(
-            "New archive candidate with url: {:?} received",
-            candidate.url
-        );
-        if db.lock().unwrap().is_track_archived(&candidate.url) {
-            debug!("Track is already archived, skipping it.");
+        debug!("Starting archive job {}", job.id);
+        if let Err(e) = db.write_blocking(|db| {
+            db.set_archive_job_status(job.id, ArchiveJobStatus::Running, None, None)
+        }) {
+            error!("Unable to start archive job {}: {e:#}", job.id);
+            std::thread::sleep(Duration::from_secs(60));
             continue;
         }
 
-        debug!("Cleaning DOWNLOAD_DIR");
-        std::fs::remove_dir_all(DOWNLOAD_DIR.clone()).unwrap();
-        std::fs::create_dir(DOWNLOAD_DIR.clone()).unwrap();
+        let result = match archive(job.candidate, &db, &mut gpt_client) {
+            Ok(track_id) => {
+                debug!("Track archived.");
+                db.write_blocking(|db| {
+                    db.set_archive_job_status(
+                        job.id,
+                        ArchiveJobStatus::Succeeded,
+                        None,
+                        Some(track_id),
+                    )?;
+                    if let Some(playlist_id) = job.playlist_id {
+                        let added = match job.playlist_position {
+                            Some(position) => {
+                                db.insert_imported_playlist_track(playlist_id, position, track_id)
+                            }
+                            None => db.add_playlist_tracks(playlist_id, &[track_id]),
+                        };
+                        if let Err(e) = added {
+                            warn!("Unable to add track {track_id} to playlist {playlist_id}: {e}");
+                        }
+                    }
+                    Ok(())
+                })
+            }
+            Err(e) => {
+                error!("Archive job {} failed: {e}", job.id);
+                db.write_blocking(|db| {
+                    db.set_archive_job_status(
+                        job.id,
+                        ArchiveJobStatus::Failed,
+                        Some(&e.to_string()),
+                        None,
+                    )
+                })
+            }
+        };
+        if let Err(e) = result {
+            error!("Unable to finish archive job {}: {e:#}", job.id);
+        }
+    }
+}
 
-        let track_id = db.lock().unwrap().next_track_id();
+// Archives the candidate and returns the id of the new track
+fn archive(
+    candidate: Candidate,
+    db: &DatabasePool,
+    gpt_client: &mut Option<Client<OpenAIConfig>>,
+) -> anyhow::Result<u32> {
+    let mut candidate = candidate.validated()?;
+    let source = candidate.source().context("Url is invalid")?;
 
-        debug!("Filling metadata");
-        if let Err(e) = pollster::block_on(fill_metadata(&mut candidate, &mut gpt_client)) {
-            error!(
-                "Unable to fill metadata for candidate: {:?} because: {e}",
-                candidate
-            );
-            continue;
-        }
+    debug!(
+        "New archive candidate with url: {:?} received",
+        candidate.url
+    );
+    // Saves downloading the track, archiving it checks again
+    let url = candidate.url.clone();
+    db.write_blocking(move |db| db.check_archivable(&url))?;
 
-        debug!("Downloading track");
-        if let Err(reason) = download_track(track_id, &candidate) {
-            error!(
-                "Unable to download track {:?} because of: {reason}",
-                candidate
-            );
-            continue;
-        }
+    debug!("Cleaning DOWNLOAD_DIR");
+    std::fs::remove_dir_all(DOWNLOAD_DIR.clone())?;
+    std::fs::create_dir(DOWNLOAD_DIR.clone())?;
 
-        debug!("Setting audio tags");
-        if let Err(e) = set_audio_tags(&candidate, track_id) {
-            warn!(
-                "Unable to set audio tags for candidate: {:?} because: {e}",
-                candidate
-            );
-        }
+    let track_id = db.write_blocking(|db| Ok(db.next_track_id()))?;
 
-        let track = Track::new(
-            track_id,
-            candidate.url,
-            candidate.title.unwrap(),
-            candidate.artists,
-            Utc::now().date_naive(),
-        );
-        let file_name = track.file_name();
-        let mut down_dest = DOWNLOAD_DIR.clone();
-        down_dest.push(format!("{}.m4a", track_id));
-        let mut path_dest = TRACK_DIR.clone();
-        path_dest.push(&file_name);
-
-        debug!("Moving track from download_dir to tracks");
-        let mut old_path = DOWNLOAD_DIR.clone();
-        old_path.push(format!("{}.m4a", track_id));
-        let mut new_path = TRACK_DIR.clone();
-        new_path.push(track.file_name());
-        if new_path.exists() {
-            error!("A track with this file name already exists, skipping archiving: {file_name}");
-            continue;
-        }
-        std::fs::rename(old_path, new_path).unwrap();
+    debug!("Filling metadata");
+    pollster::block_on(fill_metadata(&mut candidate, gpt_client))
+        .context("Unable to fill metadata")?;
 
-        debug!("Inserting track into database");
-        db.lock().unwrap().insert_tracks(once(&track));
+    debug!("Downloading track");
+    download_track(track_id, &candidate).context("Unable to download track")?;
 
-        debug!("Track archived.");
+    debug!("Setting audio tags");
+    if let Err(e) = set_audio_tags(&candidate, track_id) {
+        warn!(
+            "Unable to set audio tags for candidate: {:?} because: {e}",
+            candidate
+        );
     }
+
+    let track = Track::new(
+        track_id,
+        candidate.url,
+        source,
+        candidate.title.unwrap(),
+        candidate.artists,
+        Utc::now().date_naive(),
+    );
+    let file_name = track.file_name();
+
+    debug!("Moving track from download_dir to tracks and inserting it into database");
+    let mut old_path = DOWNLOAD_DIR.clone();
+    old_path.push(format!("{}.m4a", track_id));
+    let mut new_path = TRACK_DIR.clone();
+    new_path.push(&file_name);
+    db.write_blocking(|db| {
+        db.insert_archived_track(&track, |files| {
+            if new_path.exists() {
+                return Err(ServerError::Conflict(format!(
+                    "A track with this file name already exists: {file_name}"
+                )));
+            }
+            files.rename(&old_path, &new_path)
+        })
+    })?;
+
+    Ok(track_id)
 }
 
 // ./yt-dlp --print "%(track)s<<harmony>>%(artist)s<<harmony>>%(title)s<<harmony>>%(uploader)s"
@@ -159,7 +224,7 @@ enum RawMetadata {
 }
 
 fn get_raw_metadata(candidate: &Candidate) -> anyhow::Result<RawMetadata> {
-    let mut cmd = Command::new("yt-dlp");
+    let mut cmd = yt_dlp();
     cmd.args([
         "--print",
         "%(track)s<<harmony>>%(artist)s<<harmony>>%(title)s<<harmony>>%(uploader)s",
@@ -228,7 +293,7 @@ async fn process_metadata_using_chatgpt(
     debug!("Asking ChatGPT to extract info from video title");
     let request = CreateChatCompletionRequestArgs::default()
         .max_tokens(256u16)
-        .model("gpt-4-0125-preview")
+        .model(&config().llm.model)
         .messages([
             ChatCompletionRequestSystemMessageArgs::default()
                 .content(r#"Given is the titel of a music video. The video title contains the song title and may contain song artists. Extract the song title and artists and present them like this:
@@ -258,13 +323,25 @@ async fn process_metadata_using_chatgpt(
     Ok(response)
 }
 
+// Command calling the configured yt-dlp with the configured extra arguments
+fn yt_dlp() -> Command {
+    let yt_dlp = &config().yt_dlp;
+    let mut cmd = Command::new(&yt_dlp.path);
+    cmd.args(&yt_dlp.args);
+    cmd
+}
+
 fn download_track(id: u32, candidate: &Candidate) -> anyhow::Result<()> {
-    let mut cmd = Command::new("yt-dlp");
-    cmd.args(["-o", &format!("{}.m4a", id.to_string())]);
+    let mut cmd = yt_dlp();
+    cmd.args(["-o", &format!("{}.%(ext)s", id.to_string())]);
+    // Not every source offers m4a audio, other formats are converted
     cmd.args([
         "--no-warnings",
         "-f",
-        "bestaudio[ext=m4a]",
+        "bestaudio[ext=m4a]/bestaudio",
+        "-x",
+        "--audio-format",
+        "m4a",
         "--add-metadata",
         "--embed-metadata",
         "--xattrs",
@@ -287,13 +364,62 @@ fn download_track(id: u32, candidate: &Candidate) -> anyhow::Result<()> {
     }
 }
 
+/// Tracks and title of a playlist or channel
+pub struct ExpandedCollection {
+    pub title: Option<String>,
+    // Urls of all entries in order, not yet normalized
+    pub urls: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct FlatPlaylist {
+    title: Option<String>,
+    #[serde(default)]
+    entries: Vec<FlatPlaylistEntry>,
+}
+
+#[derive(Deserialize)]
+struct FlatPlaylistEntry {
+    url: Option<String>,
+    webpage_url: Option<String>,
+}
+
+// Lists the entries of a playlist or channel without downloading them
+pub fn expand_collection(url: &str) -> anyhow::Result<ExpandedCollection> {
+    let mut cmd = yt_dlp();
+    cmd.args(["--no-warnings", "--flat-playlist", "-J", url]);
+    cmd.stdin(Stdio::null());
+    let output = cmd.output()?;
+    if !output.status.success() {
+        bail!(
+            "yt-dlp was unable to list collection: {} because: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let playlist: FlatPlaylist = serde_json::from_slice(&output.stdout)
+        .context("yt-dlp returned an invalid playlist listing")?;
+    Ok(ExpandedCollection {
+        title: playlist.title,
+        urls: playlist
+            .entries
+            .into_iter()
+            .filter_map(|entry| entry.url.or(entry.webpage_url))
+            .collect(),
+    })
+}
+
 fn set_audio_tags(candidate: &Candidate, id: u32) -> anyhow::Result<()> {
     let mut path = DOWNLOAD_DIR.clone();
     path.push(format!("{}.m4a", id));
+    write_audio_tags(&path, candidate.title.as_ref().unwrap(), &candidate.artists)
+}
 
-    let mut tag = Tag::new().read_from_path(&path)?;
-    tag.set_title(&candidate.title.as_ref().unwrap());
-    tag.set_artist(&candidate.artists.join(", "));
-    tag.write_to_path(path.to_str().unwrap())?;
+pub fn write_audio_tags(path: &Path, title: &str, artists: &[String]) -> anyhow::Result<()> {
+    let mut tag = Tag::new().read_from_path(path)?;
+    tag.set_title(title);
+    tag.set_artist(&artists.join(", "));
+    tag.write_to_path(path.to_str().context("Path is not valid UTF-8")?)?;
     Ok(())
 }
@@ -1,113 +1,817 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
-    time::Instant,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
 };
 
+use anyhow::{anyhow, Context};
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
 use axum::{
-    extract::{Query, Request},
-    http::{header, StatusCode},
+    body::Bytes,
+    extract::{ConnectInfo, MatchedPath, Request},
+    http::{header, HeaderMap, StatusCode},
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::{AppendHeaders, IntoResponse, Response},
 };
 use axum_extra::extract::CookieJar;
-use common::token::ApiToken;
-use serde::{Deserialize, Serialize};
-use tracing::debug;
+use chrono::Utc;
+use common::{
+    api::{self, DownloadForm, Endpoint, SignedDownload, CSRF_COOKIE, CSRF_HEADER},
+    token::{AccessToken, ApiToken, CreatedAccessToken, NewAccessToken, Scope, Session},
+    user::{Credentials, NewUser, Role, User},
+};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info, warn};
 
+use crate::{
+    config::config,
+    error::ServerError,
+    pool::DatabasePool,
+    router::{nest_prefix, Route},
+    SESSION_ABSOLUTE_TTL, SESSION_SLIDING_TTL,
+};
+
+// Sessions are stored in the database, so they survive restarts. A session expires once it
+// was not used for SESSION_SLIDING_TTL or exists for longer than SESSION_ABSOLUTE_TTL.
 pub struct TokenManager {
-    secret: String,
-    tokens: Mutex<HashMap<ApiToken, Instant>>,
+    database: DatabasePool,
 }
 
 impl TokenManager {
-    pub fn new(secret: &str) -> Self {
-        Self {
-            secret: secret.to_string(),
-            tokens: Mutex::new(HashMap::new()),
-        }
+    pub fn new(database: DatabasePool) -> Self {
+        Self { database }
     }
 
-    pub fn new_token(&self) -> ApiToken {
+    pub async fn new_token(&self, user_id: u32) -> Result<ApiToken, ServerError> {
         let api_token = ApiToken::new();
-        self.tokens
-            .lock()
-            .unwrap()
-            .insert(api_token.clone(), Instant::now());
-        api_token
+        let token_hash = hash_token(api_token.as_str());
+        self.database
+            .write(move |db| db.insert_session(&token_hash, user_id))
+            .await?;
+        Ok(api_token)
+    }
+
+    pub async fn new_access_token(
+        &self,
+        user_id: u32,
+        new: NewAccessToken,
+    ) -> Result<CreatedAccessToken, ServerError> {
+        let secret = AccessToken::new_secret();
+        let token_hash = hash_token(&secret);
+        let token = self
+            .database
+            .write(move |db| db.insert_access_token(&token_hash, user_id, &new))
+            .await?;
+        Ok(CreatedAccessToken { token, secret })
+    }
+
+    // Returns the access token with the secret, None if the secret is invalid or expired
+    pub async fn access_token(&self, secret: &str) -> Result<Option<AccessToken>, ServerError> {
+        let token_hash = hash_token(secret);
+        let Some(token) = self
+            .database
+            .read(move |db| db.access_token_by_hash(&token_hash))
+            .await?
+        else {
+            return Ok(None);
+        };
+        let now = Utc::now().naive_utc();
+        if token.date_expires.is_some_and(|expires| expires < now) {
+            return Ok(None);
+        }
+        // Only write once a minute instead of on every request
+        if token
+            .date_last_used
+            .map_or(true, |used| now - used > chrono::Duration::minutes(1))
+        {
+            let id = token.id;
+            self.database
+                .write(move |db| db.touch_access_token(id, now))
+                .await?;
+        }
+        Ok(Some(token))
+    }
+
+    // Returns the session of the token, None if the token is invalid or expired
+    pub async fn token_session(&self, token: &ApiToken) -> Result<Option<Session>, ServerError> {
+        let token_hash = hash_token(token.as_str());
+        let Some(session) = self
+            .database
+            .read(move |db| db.session_by_token_hash(&token_hash))
+            .await?
+        else {
+            return Ok(None);
+        };
+        let now = Utc::now().naive_utc();
+        let id = session.id;
+        if session.date_last_seen + *SESSION_SLIDING_TTL < now
+            || session.date_created + *SESSION_ABSOLUTE_TTL < now
+        {
+            self.database.write(move |db| db.delete_session(id)).await?;
+            return Ok(None);
+        }
+        // Only write once a minute instead of on every request
+        if now - session.date_last_seen > chrono::Duration::minutes(1) {
+            self.database
+                .write(move |db| db.touch_session(id, now))
+                .await?;
+        }
+        Ok(Some(session))
+    }
+
+    // Returns false if the session does not exist
+    pub async fn revoke_session(&self, id: u32) -> Result<bool, ServerError> {
+        self.database.write(move |db| db.delete_session(id)).await
+    }
+
+    // Deletes all expired sessions and returns how many
+    pub async fn sweep(&self) -> Result<usize, ServerError> {
+        let now = Utc::now().naive_utc();
+        self.database
+            .write(move |db| {
+                db.delete_expired_sessions(now - *SESSION_SLIDING_TTL, now - *SESSION_ABSOLUTE_TTL)
+            })
+            .await
+    }
+}
+
+// Only hashes of tokens are stored, so a leaked database does not leak sessions
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Sent back in CSRF_HEADER, derived from the api_token so it can not be forged without it
+fn csrf_token(api_token: &str) -> String {
+    hash_token(&format!("csrf:{api_token}"))
+}
+
+// Compares secrets without revealing through its timing how much of them matches
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Signed download urls are followed right away, so they need not stay valid for long
+const DOWNLOAD_URL_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Signs download urls, so the browser can download tracks without a credential in the url.
+/// The key only lives in memory, urls signed before a restart are rejected after it.
+pub struct DownloadSigner {
+    key: [u8; 32],
+}
+
+impl DownloadSigner {
+    pub fn new() -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self { key }
+    }
+
+    pub fn sign(&self, ids: &[u32]) -> SignedDownload {
+        let ids = DownloadForm::new(ids).ids;
+        let expires = Utc::now().timestamp() + DOWNLOAD_URL_TTL.as_secs() as i64;
+        let signature = self.signature(&ids, expires);
+        SignedDownload {
+            ids,
+            expires,
+            signature,
+        }
+    }
+
+    pub fn verify(&self, download: &SignedDownload) -> Result<(), ServerError> {
+        let signature = self.signature(&download.ids, download.expires);
+        if !constant_time_eq(&signature, &download.signature) {
+            return Err(ServerError::Forbidden(
+                "The signature of the download url is invalid".to_string(),
+            ));
+        }
+        if download.expires < Utc::now().timestamp() {
+            return Err(ServerError::Forbidden(
+                "The download url expired".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn signature(&self, ids: &str, expires: i64) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(format!("{ids}:{expires}").as_bytes());
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+}
+
+impl Default for DownloadSigner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn session_sweeper_task(token_manager: Arc<TokenManager>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(10 * 60));
+    loop {
+        interval.tick().await;
+        match token_manager.sweep().await {
+            Ok(0) => {}
+            Ok(swept) => debug!("Swept {swept} expired sessions"),
+            Err(e) => error!("Unable to sweep expired sessions: {e:#}"),
+        }
     }
+}
+
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Unable to hash password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+// Hash checked when the user does not exist, so unknown names take as long as wrong passwords
+static DUMMY_PASSWORD_HASH: Lazy<String> =
+    Lazy::new(|| hash_password("not the password of anyone").unwrap());
+
+// Comparison happens in constant time, so the time taken reveals nothing about the hash
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
 
-    pub fn token_valid(&self, token: &ApiToken) -> bool {
-        let mut tokens = self.tokens.lock().unwrap();
-        if let Some(time) = tokens.get_mut(token) {
-            if time.elapsed().as_secs() > 60 * 60 {
-                tokens.remove(token);
-                return false;
+// Creates the first admin if there are no users yet
+pub async fn ensure_admin(database: &DatabasePool) -> anyhow::Result<()> {
+    if !database.read(|db| Ok(db.all_users()?.is_empty())).await? {
+        return Ok(());
+    }
+    let name = std::env::var("HARMONY_ADMIN_NAME").unwrap_or("admin".to_string());
+    // The shared SECRET of older versions becomes the password of the first admin
+    let password = std::env::var("HARMONY_ADMIN_PASSWORD")
+        .or_else(|_| std::env::var("SECRET"))
+        .context("No users exist, set HARMONY_ADMIN_PASSWORD to create the first admin")?;
+    let user = NewUser {
+        name,
+        password,
+        role: Role::Admin,
+    }
+    .validated()
+    .context("HARMONY_ADMIN_NAME or HARMONY_ADMIN_PASSWORD is invalid")?;
+    let user = database
+        .write(move |db| {
+            let password_hash = hash_password(&user.password)?;
+            db.insert_user(&user, &password_hash)
+        })
+        .await?;
+    info!("Created the first admin: {}", user.name);
+    Ok(())
+}
+
+// Fails with 403 if the user's role is below the required one
+pub fn require_role(user: &User, role: Role) -> Result<(), ServerError> {
+    if user.role < role {
+        return Err(ServerError::Forbidden(format!(
+            "This requires the {} role",
+            role.as_str()
+        )));
+    }
+    Ok(())
+}
+
+/// Locks out addresses after repeated failed logins, doubling the lockout with every failure
+pub struct LoginLimiter {
+    failures: Mutex<HashMap<IpAddr, FailedLogins>>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+struct FailedLogins {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Instant,
+}
+
+impl LoginLimiter {
+    // Failures allowed before the first lockout
+    const FREE_ATTEMPTS: u32 = 5;
+    const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+    const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+    pub fn new(trusted_proxies: Vec<IpAddr>) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            trusted_proxies,
+        }
+    }
+
+    // Address of the client behind the trusted proxies. Only requests from trusted proxies may
+    // name their client, and the chain of forwarded addresses is walked from the right since
+    // clients can prepend any address they want.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusted_proxies.contains(&peer) {
+            return peer;
+        }
+        let forwarded: Vec<&str> = headers
+            .get_all(header::FORWARDED)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim().eq_ignore_ascii_case("for").then_some(value)
+                })
+            })
+            .collect();
+        let chain = if !forwarded.is_empty() {
+            forwarded
+        } else {
+            headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .collect()
+        };
+
+        let mut client = peer;
+        for node in chain.into_iter().rev() {
+            match parse_forwarded_node(node) {
+                Some(ip) if self.trusted_proxies.contains(&client) => client = ip,
+                // Obfuscated or unknown nodes can not be told apart, so the request is limited
+                // by the address of the proxy
+                _ => break,
             }
-            *time = Instant::now();
-            return true;
         }
-        false
+        client
+    }
+
+    // Reserves a login attempt, which counts as failed until succeed is called, so parallel
+    // attempts can not get past the lockout while their passwords are verified. Returns the
+    // number of failures and the lockout that apply if it fails, or the remaining lockout if
+    // the address may not try yet.
+    pub fn attempt(&self, ip: IpAddr) -> Result<(u32, Duration), Duration> {
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        // Addresses which stopped failing are forgotten
+        failures.retain(|_, f| now.duration_since(f.last_failure) < Self::MAX_LOCKOUT);
+
+        let entry = failures.entry(ip).or_insert(FailedLogins {
+            count: 0,
+            last_failure: now,
+            locked_until: now,
+        });
+        let remaining = entry.locked_until.saturating_duration_since(now);
+        if !remaining.is_zero() {
+            return Err(remaining);
+        }
+        entry.count += 1;
+        entry.last_failure = now;
+        let lockout = Self::lockout_after(entry.count);
+        entry.locked_until = now + lockout;
+        Ok((entry.count, lockout))
+    }
+
+    // Lockout after the given number of failures in a row
+    fn lockout_after(failures: u32) -> Duration {
+        match failures.checked_sub(Self::FREE_ATTEMPTS) {
+            Some(exponent) => Self::BASE_LOCKOUT
+                .saturating_mul(2u32.saturating_pow(exponent))
+                .min(Self::MAX_LOCKOUT),
+            None => Duration::ZERO,
+        }
+    }
+
+    // Refunds the attempt and forgets the earlier failures of the address
+    pub fn succeed(&self, ip: IpAddr) {
+        self.failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&ip);
     }
 }
 
-pub async fn use_secret(token_manager: Arc<TokenManager>, body: String) -> Response {
-    debug!("use_secret fired with body: {body}");
-    if body != token_manager.secret {
-        return StatusCode::UNAUTHORIZED.into_response();
+// Parses a node of the Forwarded or X-Forwarded-For header, e.g. "192.0.2.1",
+// "\"192.0.2.1:4711\"" or "\"[2001:db8::1]:4711\""
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
     }
-    let token = token_manager.new_token();
-    debug!("Created token: {:?}", token);
-    (
-        [
-            (header::CONTENT_TYPE, "application/octet-stream"),
-            (
-                header::SET_COOKIE,
-                &format!("api_token={}; SameSite=None; Secure", token.as_str()),
-            ),
-        ],
-        serde_json::to_string(&token).unwrap(),
-    )
-        .into_response()
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|address| address.ip()))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TokenQuery {
-    api_token: Option<String>,
+// Attributes of the session cookies. Secure ones are only sent over HTTPS, which servers
+// reached over plain HTTP have to turn off.
+fn cookie_attributes() -> &'static str {
+    if config().session.secure_cookies {
+        "Path=/; SameSite=Strict; Secure"
+    } else {
+        "Path=/; SameSite=Strict"
+    }
 }
 
+pub async fn login(
+    database: DatabasePool,
+    token_manager: Arc<TokenManager>,
+    limiter: Arc<LoginLimiter>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ServerError> {
+    let credentials: Credentials = serde_json::from_slice(&body)
+        // The error is not returned since it may quote the body
+        .map_err(|_| ServerError::bad_request("Invalid credentials format"))?;
+
+    let ip = limiter.client_ip(address.ip(), &headers);
+    let (failures, lockout) = match limiter.attempt(ip) {
+        Ok(attempt) => attempt,
+        Err(remaining) => {
+            return Err(ServerError::TooManyRequests {
+                message: "Too many failed logins, try again later".to_string(),
+                retry_after: remaining,
+            })
+        }
+    };
+    let name = credentials.name.trim().to_string();
+    debug!("login fired with {:?}", credentials);
+    let user = {
+        let name = name.clone();
+        database.read(move |db| db.user_credentials(&name)).await?
+    };
+    let (user, password_hash) = match user {
+        Some((user, password_hash)) => (Some(user), password_hash),
+        None => (None, DUMMY_PASSWORD_HASH.clone()),
+    };
+    // Hashing is slow on purpose, so it must not block the runtime
+    let valid =
+        tokio::task::spawn_blocking(move || verify_password(&credentials.password, &password_hash))
+            .await
+            .map_err(|e| ServerError::Internal(e.into()))?;
+    let user = match user {
+        Some(user) if valid => user,
+        _ => {
+            warn!(
+                %ip,
+                user = %name,
+                known_user = user.is_some(),
+                failures,
+                lockout_secs = lockout.as_secs(),
+                "Failed login attempt"
+            );
+            return Err(ServerError::Unauthorized(
+                "Invalid name or password".to_string(),
+            ));
+        }
+    };
+    limiter.succeed(ip);
+    let token = token_manager.new_token(user.id).await?;
+    debug!("Created token for user: {}", user.name);
+    // Scripts can not read the api_token cookie, but the client needs the CSRF token
+    let attributes = cookie_attributes();
+    Ok(AppendHeaders([
+        (
+            header::SET_COOKIE,
+            format!("api_token={}; HttpOnly; {attributes}", token.as_str()),
+        ),
+        (
+            header::SET_COOKIE,
+            format!("{CSRF_COOKIE}={}; {attributes}", csrf_token(token.as_str())),
+        ),
+    ])
+    .into_response())
+}
+
+/// How the current request was authenticated
+#[derive(Debug, Clone)]
+pub enum Credential {
+    Session(Session),
+    AccessToken(AccessToken),
+}
+
+// Resolves the user and credential of the request and makes both available to handlers as
+// Extensions. Access tokens are sent as "Authorization: Bearer", sessions as api_token cookie.
 pub async fn auth_middleware(
     jar: CookieJar,
-    Query(token_query): Query<TokenQuery>,
     token_manager: Arc<TokenManager>,
-    request: Request,
+    database: DatabasePool,
+    routes: Arc<[Route]>,
+    mut request: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
-    // Check if api_token cookie is set
-    let api_token = match jar.get("api_token") {
-        Some(token) => token.value().to_string(),
-        None => {
-            // Check if api_token is in headers
-            match request.headers().get("api_token") {
-                Some(token) => match token.to_str() {
-                    Ok(api_token) => api_token.to_string(),
-                    Err(_) => return Err(StatusCode::UNAUTHORIZED),
-                },
-                None => {
-                    // Check if the api_token is in the Query
-                    match token_query.api_token {
-                        Some(token) => token,
-                        None => return Err(StatusCode::UNAUTHORIZED),
-                    }
-                }
-            }
+) -> Result<Response, ServerError> {
+    if let Some(authorization) = request.headers().get(header::AUTHORIZATION) {
+        let invalid = || ServerError::Unauthorized("The access token is invalid or expired".into());
+        let secret = authorization
+            .to_str()
+            .ok()
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .ok_or_else(invalid)?;
+        let token = token_manager
+            .access_token(secret.trim())
+            .await?
+            .ok_or_else(invalid)?;
+        let path = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str())
+            .unwrap_or_default();
+        let path = path.strip_prefix(nest_prefix(&request)).unwrap_or(path);
+        // Requests matching no endpoint are refused like those of endpoints needing admin
+        let scope = routes
+            .iter()
+            .find(|route| route.path == path && route.method.as_str() == request.method())
+            .and_then(|route| route.scope)
+            .unwrap_or(Scope::Admin);
+        if !token.allows(scope) {
+            return Err(ServerError::Forbidden(format!(
+                "The access token lacks the {} scope",
+                scope.as_str()
+            )));
         }
+        let user_id = token.user_id;
+        let user = database
+            .read(move |db| db.user(user_id))
+            .await?
+            .ok_or_else(invalid)?;
+        request.extensions_mut().insert(user);
+        request
+            .extensions_mut()
+            .insert(Credential::AccessToken(token));
+        return Ok(next.run(request).await);
+    }
+
+    let not_logged_in = || ServerError::Unauthorized("Not logged in or the session expired".into());
+    // Sessions are only ever held by the HttpOnly cookie, so scripts can not leak them. Tokens
+    // are never read from the query, as urls end up in logs and the history.
+    let Some(api_token) = jar.get("api_token").map(|token| token.value().to_string()) else {
+        return Err(not_logged_in());
     };
 
-    if !token_manager.token_valid(&ApiToken::from_string(&api_token)) {
-        return Err(StatusCode::UNAUTHORIZED);
+    let Some(session) = token_manager
+        .token_session(&ApiToken::from_string(&api_token))
+        .await?
+    else {
+        return Err(not_logged_in());
+    };
+    // Other sites can make browsers send the cookie as well, so changes through it also need
+    // the CSRF token, which only pages of this origin can read
+    if !request.method().is_safe() {
+        let valid = request
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|csrf| csrf.to_str().ok())
+            .is_some_and(|csrf| constant_time_eq(csrf, &csrf_token(&api_token)));
+        if !valid {
+            return Err(ServerError::Forbidden(format!(
+                "Requests authenticated by cookie need the {CSRF_HEADER} header"
+            )));
+        }
     }
+    // Users are read on every request, so role changes and deletions apply right away
+    let user_id = session.user_id;
+    let Some(user) = database.read(move |db| db.user(user_id)).await? else {
+        token_manager.revoke_session(session.id).await?;
+        return Err(not_logged_in());
+    };
+    request.extensions_mut().insert(user);
+    request
+        .extensions_mut()
+        .insert(Credential::Session(session));
 
     let response = next.run(request).await;
     Ok(response)
 }
+
+// Revokes the session the request was made with and removes its cookies
+pub async fn logout(
+    token_manager: Arc<TokenManager>,
+    credential: Credential,
+) -> Result<Response, ServerError> {
+    let Credential::Session(session) = credential else {
+        return Err(ServerError::bad_request(format!(
+            "Access tokens are revoked through {} /api{}",
+            api::RevokeAccessToken::METHOD.as_str(),
+            api::RevokeAccessToken::ROUTE
+        )));
+    };
+    token_manager.revoke_session(session.id).await?;
+    let attributes = cookie_attributes();
+    Ok((
+        AppendHeaders([
+            (
+                header::SET_COOKIE,
+                format!("api_token=; Max-Age=0; HttpOnly; {attributes}"),
+            ),
+            (
+                header::SET_COOKIE,
+                format!("{CSRF_COOKIE}=; Max-Age=0; {attributes}"),
+            ),
+        ]),
+        StatusCode::OK,
+    )
+        .into_response())
+}
+
+// Lists the sessions of the user, admins see the sessions of all users
+pub async fn get_sessions(database: DatabasePool, user: User) -> Result<String, ServerError> {
+    let user_id = (user.role < Role::Admin).then_some(user.id);
+    let sessions = database.read(move |db| db.sessions(user_id)).await?;
+    Ok(serde_json::to_string(&sessions).unwrap())
+}
+
+// Users may revoke their own sessions, admins those of every user
+pub async fn revoke_session(
+    database: DatabasePool,
+    token_manager: Arc<TokenManager>,
+    user: User,
+    id: u32,
+) -> Result<(), ServerError> {
+    let not_found = || ServerError::NotFound(format!("No session with id {id} exists"));
+    let Some(session) = database.read(move |db| db.session(id)).await? else {
+        return Err(not_found());
+    };
+    if session.user_id != user.id {
+        // Sessions of other users are not revealed to non admins
+        require_role(&user, Role::Admin).map_err(|_| not_found())?;
+    }
+    token_manager.revoke_session(id).await?;
+    Ok(())
+}
+
+// Lists the access tokens of the user, admins see the tokens of all users
+pub async fn get_access_tokens(database: DatabasePool, user: User) -> Result<String, ServerError> {
+    let user_id = (user.role < Role::Admin).then_some(user.id);
+    let tokens = database.read(move |db| db.access_tokens(user_id)).await?;
+    Ok(serde_json::to_string(&tokens).unwrap())
+}
+
+// Creates an access token for the user, its secret is only part of this response
+pub async fn create_access_token(
+    token_manager: Arc<TokenManager>,
+    user: User,
+    body: Bytes,
+) -> Result<String, ServerError> {
+    let new: NewAccessToken = serde_json::from_slice(&body).map_err(ServerError::bad_request)?;
+    let new = new.validated().map_err(ServerError::bad_request)?;
+    if new
+        .date_expires
+        .is_some_and(|expires| expires <= Utc::now().naive_utc())
+    {
+        return Err(ServerError::bad_request("Expiry must be in the future"));
+    }
+    let created = token_manager.new_access_token(user.id, new).await?;
+    info!(
+        user = %user.name,
+        token = %created.token.name,
+        "Created access token"
+    );
+    Ok(serde_json::to_string(&created).unwrap())
+}
+
+// Users may revoke their own access tokens, admins those of every user
+pub async fn revoke_access_token(
+    database: DatabasePool,
+    user: User,
+    id: u32,
+) -> Result<(), ServerError> {
+    let not_found = || ServerError::NotFound(format!("No access token with id {id} exists"));
+    let Some(token) = database.read(move |db| db.access_token(id)).await? else {
+        return Err(not_found());
+    };
+    if token.user_id != user.id {
+        // Tokens of other users are not revealed to non admins
+        require_role(&user, Role::Admin).map_err(|_| not_found())?;
+    }
+    database.write(move |db| db.delete_access_token(id)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles_after_the_free_attempts() {
+        for failures in 0..LoginLimiter::FREE_ATTEMPTS {
+            assert_eq!(LoginLimiter::lockout_after(failures), Duration::ZERO);
+        }
+        assert_eq!(LoginLimiter::lockout_after(5), Duration::from_secs(1));
+        assert_eq!(LoginLimiter::lockout_after(6), Duration::from_secs(2));
+        assert_eq!(LoginLimiter::lockout_after(10), Duration::from_secs(32));
+        assert_eq!(LoginLimiter::lockout_after(16), Duration::from_secs(2048));
+        assert_eq!(LoginLimiter::lockout_after(17), LoginLimiter::MAX_LOCKOUT);
+        assert_eq!(
+            LoginLimiter::lockout_after(u32::MAX),
+            LoginLimiter::MAX_LOCKOUT
+        );
+    }
+
+    #[test]
+    fn attempts_are_reserved_until_they_succeed() {
+        let limiter = LoginLimiter::new(Vec::new());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        for failures in 1..LoginLimiter::FREE_ATTEMPTS {
+            assert_eq!(limiter.attempt(ip), Ok((failures, Duration::ZERO)));
+        }
+        assert_eq!(limiter.attempt(ip), Ok((5, Duration::from_secs(1))));
+        // The lockout applies while the last attempt is still being verified
+        let remaining = limiter.attempt(ip).unwrap_err();
+        assert!(!remaining.is_zero() && remaining <= Duration::from_secs(1));
+        assert_eq!(limiter.attempt(other), Ok((1, Duration::ZERO)));
+
+        limiter.succeed(ip);
+        assert_eq!(limiter.attempt(ip), Ok((1, Duration::ZERO)));
+    }
+
+    #[test]
+    fn client_addresses_are_only_forwarded_by_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let limiter = LoginLimiter::new(vec![proxy, "10.0.0.2".parse().unwrap()]);
+        let client_ip = |peer: &str, headers: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.append(*name, value.parse().unwrap());
+            }
+            limiter.client_ip(peer.parse().unwrap(), &map).to_string()
+        };
+
+        let spoofed = [("x-forwarded-for", "192.0.2.1")];
+        assert_eq!(client_ip("198.51.100.1", &spoofed), "198.51.100.1");
+        assert_eq!(client_ip("10.0.0.1", &[]), "10.0.0.1");
+        assert_eq!(client_ip("10.0.0.1", &spoofed), "192.0.2.1");
+        // Addresses prepended by the client are ignored
+        let chained = [("x-forwarded-for", "192.0.2.1, 203.0.113.7, 10.0.0.2")];
+        assert_eq!(client_ip("10.0.0.1", &chained), "203.0.113.7");
+
+        let forwarded = [
+            ("forwarded", "for=192.0.2.1"),
+            (
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https, For=\"10.0.0.2:80\"",
+            ),
+            ("x-forwarded-for", "192.0.2.1"),
+        ];
+        assert_eq!(client_ip("10.0.0.1", &forwarded), "2001:db8::1");
+        let obfuscated = [("forwarded", "for=192.0.2.1, for=_hidden, for=10.0.0.2")];
+        assert_eq!(client_ip("10.0.0.1", &obfuscated), "10.0.0.2");
+    }
+
+    #[test]
+    fn download_urls_only_hold_for_what_was_signed() {
+        let signer = DownloadSigner::new();
+        let signed = signer.sign(&[1, 3]);
+        assert_eq!(signed.ids, "1,3");
+        assert!(signer.verify(&signed).is_ok());
+
+        let rejected = |download: SignedDownload| {
+            matches!(signer.verify(&download), Err(ServerError::Forbidden(_)))
+        };
+        assert!(rejected(SignedDownload {
+            ids: "1,2,3".to_string(),
+            ..signed.clone()
+        }));
+        assert!(rejected(SignedDownload {
+            expires: signed.expires + 60,
+            ..signed.clone()
+        }));
+        assert!(rejected(SignedDownload {
+            signature: "not hex".to_string(),
+            ..signed.clone()
+        }));
+        assert!(rejected(SignedDownload {
+            signature: signed.signature[..10].to_string(),
+            ..signed.clone()
+        }));
+        assert!(rejected(SignedDownload {
+            signature: signed.signature.to_uppercase(),
+            ..signed.clone()
+        }));
+
+        let expires = Utc::now().timestamp() - 1;
+        assert!(rejected(SignedDownload {
+            signature: signer.signature(&signed.ids, expires),
+            expires,
+            ..signed.clone()
+        }));
+        // The key of a restarted server is a new one
+        assert!(matches!(
+            DownloadSigner::new().verify(&signed),
+            Err(ServerError::Forbidden(_))
+        ));
+    }
+}
@@ -0,0 +1,69 @@
+//! The web client, embedded into the binary so a single process serves the client and the API
+
+use std::path::Path;
+
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use rust_embed::RustEmbed;
+
+// Built with `trunk build --release` before the server, see build.sh. Debug builds read the
+// files from disk instead, so a rebuilt client is served without rebuilding the server.
+#[derive(RustEmbed)]
+#[folder = "../client/dist"]
+#[allow_missing = true]
+struct ClientDist;
+
+const INDEX: &str = "index.html";
+
+/// Serves the files of the client. Any other path is a route of the client, which gets
+/// index.html and is resolved by the client's router.
+pub async fn serve_client(uri: Uri, headers: HeaderMap) -> Response {
+    let path = match uri.path().trim_start_matches('/') {
+        "" => INDEX,
+        path => path,
+    };
+    let file = match ClientDist::get(path) {
+        Some(file) => file,
+        // Missing files must not be answered with the page
+        None if Path::new(path).extension().is_some() => {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        None => match ClientDist::get(INDEX) {
+            Some(file) => file,
+            None => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    "The client was not built, run `trunk build --release` in client",
+                )
+                    .into_response();
+            }
+        },
+    };
+
+    let etag = format!(
+        "\"{}\"",
+        file.metadata
+            .sha256_hash()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+    );
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+    (
+        [
+            (header::CONTENT_TYPE, file.metadata.mimetype().to_string()),
+            (header::ETAG, etag),
+            // Only trunk's bundles have hashed names, so everything is revalidated
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        file.data,
+    )
+        .into_response()
+}
@@ -0,0 +1,415 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use axum::http::HeaderValue;
+use clap::Parser;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+// Read when no config file is given and it exists
+const DEFAULT_CONFIG_PATH: &str = "./harmony.toml";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+// Returns the configuration loaded at startup
+pub fn config() -> &'static Config {
+    CONFIG
+        .get()
+        .expect("Expected the configuration to be loaded at startup")
+}
+
+/// Options of the server. Every option can also be set through its environment variable and
+/// takes precedence over the config file.
+#[derive(Debug, Parser)]
+#[command(version, about = "Harmony music archive server")]
+struct Cli {
+    /// Path of the TOML config file [default: ./harmony.toml if it exists]
+    #[arg(long, env = "HARMONY_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address and port to listen on
+    #[arg(long, env = "HARMONY_BIND")]
+    bind: Option<SocketAddr>,
+    /// Directory of the database, tracks and trash
+    #[arg(long, env = "HARMONY_ARCHIVE_DIR")]
+    archive_dir: Option<PathBuf>,
+    /// Tracing filter, e.g. "server=debug"
+    #[arg(long, env = "RUST_LOG")]
+    log_level: Option<String>,
+    /// Origins allowed to make cross origin requests with credentials, "*" allows all origins
+    /// but without cookies
+    #[arg(long, env = "HARMONY_CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Vec<String>,
+    /// Addresses of reverse proxies whose X-Forwarded-For and Forwarded headers identify the
+    /// clients that logins are limited by
+    #[arg(long, env = "HARMONY_TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Vec<IpAddr>,
+    #[arg(long, env = "HARMONY_TRASH_RETENTION_DAYS")]
+    trash_retention_days: Option<u32>,
+    /// Sessions end when they are not used for this long
+    #[arg(long, env = "HARMONY_SESSION_SLIDING_TTL_MINUTES")]
+    session_sliding_ttl_minutes: Option<u32>,
+    /// Sessions end after this long, even when they are used
+    #[arg(long, env = "HARMONY_SESSION_ABSOLUTE_TTL_HOURS")]
+    session_absolute_ttl_hours: Option<u32>,
+    /// Whether browsers may only send the session cookies over HTTPS, false allows plain HTTP
+    #[arg(long, env = "HARMONY_SESSION_SECURE_COOKIES")]
+    session_secure_cookies: Option<bool>,
+    /// Path of the yt-dlp executable
+    #[arg(long, env = "HARMONY_YT_DLP_PATH")]
+    yt_dlp_path: Option<PathBuf>,
+    /// Extra arguments passed to every yt-dlp call, separated by spaces
+    #[arg(
+        long,
+        env = "HARMONY_YT_DLP_ARGS",
+        value_delimiter = ' ',
+        allow_hyphen_values = true
+    )]
+    yt_dlp_args: Vec<String>,
+    /// Model used to extract titles and artists from video titles
+    #[arg(long, env = "HARMONY_LLM_MODEL")]
+    llm_model: Option<String>,
+    /// Base url of an OpenAI compatible API
+    #[arg(long, env = "HARMONY_LLM_API_BASE")]
+    llm_api_base: Option<String>,
+    /// The LLM is only used if an API key is set
+    #[arg(long, env = "OPENAI_API_KEY", hide_env_values = true)]
+    llm_api_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub archive_dir: PathBuf,
+    pub log_level: String,
+    pub cors_origins: Vec<String>,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub trash_retention_days: u32,
+    pub session: SessionConfig,
+    pub yt_dlp: YtDlpConfig,
+    pub llm: LlmConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 7000)),
+            archive_dir: PathBuf::from("./harchive"),
+            log_level: "none,server=trace,common=trace".to_string(),
+            cors_origins: Vec::new(),
+            trusted_proxies: Vec::new(),
+            trash_retention_days: 30,
+            session: SessionConfig::default(),
+            yt_dlp: YtDlpConfig::default(),
+            llm: LlmConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub sliding_ttl_minutes: u32,
+    pub absolute_ttl_hours: u32,
+    pub secure_cookies: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            sliding_ttl_minutes: 60,
+            absolute_ttl_hours: 24 * 7,
+            secure_cookies: true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct YtDlpConfig {
+    pub path: PathBuf,
+    pub args: Vec<String>,
+}
+
+impl Default for YtDlpConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("yt-dlp"),
+            args: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    pub model: String,
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            model: "gpt-4-0125-preview".to_string(),
+            api_base: None,
+            api_key: None,
+        }
+    }
+}
+
+impl Config {
+    // Parses the command line, reads the config file and validates the result. Must be called
+    // once before config() is used.
+    pub fn load() -> anyhow::Result<&'static Config> {
+        let cli = Cli::parse();
+        let mut loaded = match &cli.config {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::read(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+        loaded.apply(cli);
+        loaded.validate()?;
+        if CONFIG.set(loaded).is_err() {
+            bail!("The configuration was already loaded");
+        }
+        Ok(config())
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config file {path:?}"))?;
+        toml::from_str(&raw).with_context(|| format!("Invalid config file {path:?}"))
+    }
+
+    // Options given on the command line or through the environment replace those of the file
+    fn apply(&mut self, cli: Cli) {
+        if let Some(bind) = cli.bind {
+            self.bind = bind;
+        }
+        if let Some(archive_dir) = cli.archive_dir {
+            self.archive_dir = archive_dir;
+        }
+        if let Some(log_level) = cli.log_level {
+            self.log_level = log_level;
+        }
+        if !cli.cors_origins.is_empty() {
+            self.cors_origins = cli.cors_origins;
+        }
+        if !cli.trusted_proxies.is_empty() {
+            self.trusted_proxies = cli.trusted_proxies;
+        }
+        if let Some(days) = cli.trash_retention_days {
+            self.trash_retention_days = days;
+        }
+        if let Some(minutes) = cli.session_sliding_ttl_minutes {
+            self.session.sliding_ttl_minutes = minutes;
+        }
+        if let Some(hours) = cli.session_absolute_ttl_hours {
+            self.session.absolute_ttl_hours = hours;
+        }
+        if let Some(secure) = cli.session_secure_cookies {
+            self.session.secure_cookies = secure;
+        }
+        if let Some(path) = cli.yt_dlp_path {
+            self.yt_dlp.path = path;
+        }
+        if !cli.yt_dlp_args.is_empty() {
+            self.yt_dlp.args = cli.yt_dlp_args;
+        }
+        if let Some(model) = cli.llm_model {
+            self.llm.model = model;
+        }
+        if cli.llm_api_base.is_some() {
+            self.llm.api_base = cli.llm_api_base;
+        }
+        if cli.llm_api_key.is_some() {
+            self.llm.api_key = cli.llm_api_key;
+        }
+    }
+
+    // Checks every option and creates the archive directory
+    fn validate(&mut self) -> anyhow::Result<()> {
+        if self.archive_dir.exists() {
+            if !self.archive_dir.is_dir() {
+                bail!("archive_dir {:?} is not a directory", self.archive_dir);
+            }
+        } else {
+            fs::create_dir_all(&self.archive_dir)
+                .with_context(|| format!("Unable to create archive_dir {:?}", self.archive_dir))?;
+        }
+
+        EnvFilter::try_new(&self.log_level)
+            .with_context(|| format!("log_level {:?} is not a valid filter", self.log_level))?;
+
+        if self.cors_origins.len() > 1 && self.cors_origins.iter().any(|origin| origin == "*") {
+            bail!("cors_origins must either be [\"*\"] or list the allowed origins");
+        }
+        for origin in &self.cors_origins {
+            if origin == "*" {
+                continue;
+            }
+            if !(origin.starts_with("http://") || origin.starts_with("https://"))
+                || HeaderValue::from_str(origin).is_err()
+            {
+                bail!("cors_origins entry {origin:?} must look like \"https://example.com\"");
+            }
+        }
+
+        if self.session.sliding_ttl_minutes == 0 || self.session.absolute_ttl_hours == 0 {
+            bail!("session TTLs must be greater than 0");
+        }
+        if self.session.absolute_ttl_hours as u64 * 60 < self.session.sliding_ttl_minutes as u64 {
+            bail!(
+                "session.absolute_ttl_hours must not be shorter than session.sliding_ttl_minutes"
+            );
+        }
+
+        self.yt_dlp.path = find_executable(&self.yt_dlp.path).with_context(|| {
+            format!(
+                "yt-dlp was not found at {:?}, set yt_dlp.path to its location",
+                self.yt_dlp.path
+            )
+        })?;
+
+        if self.llm.model.trim().is_empty() {
+            bail!("llm.model must not be empty");
+        }
+        if let Some(api_base) = &self.llm.api_base {
+            if !(api_base.starts_with("http://") || api_base.starts_with("https://")) {
+                bail!("llm.api_base {api_base:?} must be an http or https url");
+            }
+        }
+        Ok(())
+    }
+}
+
+// Resolves a bare program name through PATH like a shell would
+fn find_executable(path: &Path) -> anyhow::Result<PathBuf> {
+    if path.components().count() > 1 {
+        if path.is_file() {
+            return Ok(path.to_path_buf());
+        }
+        bail!("No such file");
+    }
+    let dirs = std::env::var_os("PATH").context("PATH is not set")?;
+    std::env::split_paths(&dirs)
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.is_file())
+        .context("Not found in PATH")
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // A config which passes validation, so each test can break one option. Validation creates
+    // the archive dir in dir.
+    fn valid_config(dir: &TempDir) -> Config {
+        Config {
+            archive_dir: dir.path().join("archive"),
+            yt_dlp: YtDlpConfig {
+                path: std::env::current_exe().unwrap(),
+                args: Vec::new(),
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn options_take_precedence_over_the_file() {
+        let mut config: Config = toml::from_str(
+            r#"
+            bind = "127.0.0.1:8000"
+            cors_origins = ["https://file.example.com"]
+            trash_retention_days = 10
+
+            [session]
+            sliding_ttl_minutes = 15
+
+            [llm]
+            model = "file-model"
+            "#,
+        )
+        .unwrap();
+        // The environment is shared by all tests, so only this one sets variables
+        std::env::set_var("HARMONY_TRASH_RETENTION_DAYS", "20");
+        std::env::set_var("HARMONY_LLM_MODEL", "env-model");
+        let cli = Cli::try_parse_from([
+            "server",
+            "--llm-model",
+            "cli-model",
+            "--cors-origins",
+            "https://a.example.com,https://b.example.com",
+            "--session-secure-cookies",
+            "false",
+        ]);
+        std::env::remove_var("HARMONY_TRASH_RETENTION_DAYS");
+        std::env::remove_var("HARMONY_LLM_MODEL");
+        config.apply(cli.unwrap());
+
+        assert_eq!(config.bind, "127.0.0.1:8000".parse().unwrap());
+        assert_eq!(config.session.sliding_ttl_minutes, 15);
+        assert_eq!(config.session.absolute_ttl_hours, 24 * 7);
+        assert!(!config.session.secure_cookies);
+        assert_eq!(config.trash_retention_days, 20);
+        assert_eq!(config.llm.model, "cli-model");
+        assert_eq!(
+            config.cors_origins,
+            ["https://a.example.com", "https://b.example.com"]
+        );
+    }
+
+    #[test]
+    fn unknown_options_are_refused() {
+        assert!(toml::from_str::<Config>("cors_origin = []").is_err());
+        assert!(toml::from_str::<Config>("[session]\nttl = 5").is_err());
+    }
+
+    #[test]
+    fn invalid_options_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let mut config = valid_config(&dir);
+        config.cors_origins = vec!["*".to_string()];
+        config.llm.api_base = Some("http://localhost:8080/v1".to_string());
+        config.validate().unwrap();
+        assert!(config.archive_dir.is_dir());
+
+        type Breakage = fn(&mut Config);
+        let breakages: [(&str, Breakage); 8] = [
+            ("log_level", |c| c.log_level = "server=loud".to_string()),
+            ("cors_mixed", |c| {
+                c.cors_origins = vec!["*".to_string(), "https://example.com".to_string()]
+            }),
+            ("cors_scheme", |c| {
+                c.cors_origins = vec!["example.com".to_string()]
+            }),
+            ("sliding_ttl", |c| c.session.sliding_ttl_minutes = 0),
+            ("absolute_ttl", |c| {
+                c.session.absolute_ttl_hours = 1;
+                c.session.sliding_ttl_minutes = 61;
+            }),
+            ("yt_dlp", |c| {
+                c.yt_dlp.path = PathBuf::from("./missing/yt-dlp")
+            }),
+            ("llm_model", |c| c.llm.model = " ".to_string()),
+            ("llm_api_base", |c| {
+                c.llm.api_base = Some("localhost:8080".to_string())
+            }),
+        ];
+        for (name, breakage) in breakages {
+            let mut config = valid_config(&dir);
+            breakage(&mut config);
+            assert!(config.validate().is_err(), "{name} was accepted");
+        }
+    }
+}
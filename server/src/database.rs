@@ -1,17 +1,41 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use chrono::NaiveDate;
-use common::track::Track;
+use anyhow::Context;
+use chrono::{NaiveDateTime, Utc};
+use common::{
+    archive_job::{ArchiveJob, ArchiveJobStatus},
+    candidate::Candidate,
+    playlist::Playlist,
+    source::SourceKind,
+    token::{AccessToken, NewAccessToken, Scope, Session},
+    track::{SortDirection, Track, TrackPage, TrackQuery, TrackSortField, TrashedTrack},
+    user::{NewUser, Role, User},
+};
 use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use tracing::{error, warn};
+
+use crate::{error::ServerError, migrations};
+
+// How long a connection waits for a lock held by another connection before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct Database {
     con: Connection,
     next_track_id: u32,
     next_artist_id: u32,
+    next_archive_job_id: u32,
+    next_playlist_id: u32,
+    next_user_id: u32,
+    next_session_id: u32,
+    next_access_token_id: u32,
 }
 
 impl Database {
-    pub fn new(mut archive_dir: PathBuf) -> Self {
+    pub fn new(mut archive_dir: PathBuf) -> anyhow::Result<Self> {
         // Create/open file
         archive_dir.push("harmony.db3");
         let mut con = Connection::open_with_flags(
@@ -20,70 +44,64 @@ impl Database {
                 | OpenFlags::SQLITE_OPEN_CREATE
                 | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )
-        .expect(&format!(
-            "Expected to be able to open/create {:?}",
-            archive_dir
-        ));
-
-        // Create tables
-        let tx = con.transaction().unwrap();
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS tracks(
-            id INTEGER NOT NULL PRIMARY KEY,
-            url TEXT NOT NULL,
-            title TEXT NOT NULL,
-            date_archived TEXT NOT NULL);",
-            [],
-        )
-        .unwrap();
-
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS track_artists(
-            track_id INTEGER NOT NULL,
-            artist_id INTEGER NOT NULL,
-            PRIMARY KEY (track_id, artist_id));",
-            [],
-        )
-        .unwrap();
-
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS artists(
-            id INTEGER NOT NULL PRIMARY KEY,
-            name TEXT NOT NULL);",
-            [],
-        )
-        .unwrap();
-        tx.commit().unwrap();
+        .with_context(|| format!("Unable to open/create {:?}", archive_dir))?;
+        // SQLite only enforces foreign keys if they are enabled for the connection
+        con.pragma_update(None, "foreign_keys", true)?;
+        // Readers do not wait for the writer in WAL mode
+        con.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        con.busy_timeout(BUSY_TIMEOUT)?;
+        migrations::migrate(&mut con)?;
 
         // Get next ids
-        let next_track_id = con
-            .query_row("select id from tracks ORDER BY id DESC LIMIT 1;", [], |v| {
-                let result: u32 = v.get(0).unwrap();
-                Ok(result)
-            })
-            .optional()
-            .expect("Expected query to work")
-            .unwrap_or_default()
-            + 1;
-
-        let next_artist_id = con
-            .query_row(
-                "select id from artists ORDER BY id DESC LIMIT 1;",
-                [],
-                |v| {
-                    let result: u32 = v.get(0).unwrap();
-                    Ok(result)
-                },
-            )
-            .optional()
-            .expect("Expected query to work")
-            .unwrap_or_default()
-            + 1;
+        // Trashed tracks keep their id, so it must not be handed out again
+        let next_track_id = max_id(&con, "SELECT id FROM tracks UNION SELECT id FROM trash")? + 1;
+        let next_artist_id = max_id(&con, "SELECT id FROM artists")? + 1;
+        let next_archive_job_id = max_id(&con, "SELECT id FROM archive_jobs")? + 1;
+        let next_playlist_id = max_id(&con, "SELECT id FROM playlists")? + 1;
+        let next_user_id = max_id(&con, "SELECT id FROM users")? + 1;
+        let next_session_id = max_id(&con, "SELECT id FROM sessions")? + 1;
+        let next_access_token_id = max_id(&con, "SELECT id FROM access_tokens")? + 1;
 
-        Self {
+        Ok(Self {
             con,
             next_track_id,
             next_artist_id,
+            next_archive_job_id,
+            next_playlist_id,
+            next_user_id,
+            next_session_id,
+            next_access_token_id,
+        })
+    }
+
+    // Opens a read only connection to a database created by new. Ids are only handed out by the
+    // writer, so the next ids of readers are never used.
+    pub fn open_reader(mut archive_dir: PathBuf) -> anyhow::Result<Self> {
+        archive_dir.push("harmony.db3");
+        let con = Connection::open_with_flags(
+            &archive_dir,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .with_context(|| format!("Unable to open {:?}", archive_dir))?;
+        con.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Self {
+            con,
+            next_track_id: 0,
+            next_artist_id: 0,
+            next_archive_job_id: 0,
+            next_playlist_id: 0,
+            next_user_id: 0,
+            next_session_id: 0,
+            next_access_token_id: 0,
+        })
+    }
+
+    // Rolls back the transaction a panic left open, if there is one
+    pub fn rollback(&mut self) {
+        if !self.con.is_autocommit() {
+            if let Err(e) = self.con.execute_batch("ROLLBACK") {
+                error!("Unable to roll back an unfinished transaction: {e}");
+            }
         }
     }
 
@@ -97,213 +115,1464 @@ impl Database {
         self.next_artist_id - 1
     }
 
+    pub fn next_archive_job_id(&mut self) -> u32 {
+        self.next_archive_job_id += 1;
+        self.next_archive_job_id - 1
+    }
+
+    pub fn next_playlist_id(&mut self) -> u32 {
+        self.next_playlist_id += 1;
+        self.next_playlist_id - 1
+    }
+
+    pub fn next_user_id(&mut self) -> u32 {
+        self.next_user_id += 1;
+        self.next_user_id - 1
+    }
+
+    pub fn next_session_id(&mut self) -> u32 {
+        self.next_session_id += 1;
+        self.next_session_id - 1
+    }
+
+    pub fn next_access_token_id(&mut self) -> u32 {
+        self.next_access_token_id += 1;
+        self.next_access_token_id - 1
+    }
+
     // Insert or replace tracks
-    pub fn insert_tracks<'a>(&mut self, tracks: impl Iterator<Item = &'a Track> + Clone) {
+    pub fn insert_tracks<'a>(
+        &mut self,
+        tracks: impl Iterator<Item = &'a Track> + Clone,
+    ) -> Result<(), ServerError> {
         // Insert tracks
-        let tx = self.con.transaction().unwrap();
+        let tx = self.con.transaction()?;
         {
             let tracks = tracks.clone();
-            let mut track_stmt = tx
-                .prepare(
-                    "REPLACE INTO tracks (id, url, title, date_archived) VALUES (?1, ?2, ?3, ?4)",
-                )
-                .unwrap();
+            let mut track_stmt = tx.prepare(
+                "REPLACE INTO tracks (id, url, title, date_archived, source)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
             for track in tracks {
                 let values = (
                     track.id(),
                     track.url(),
                     track.title(),
                     track.date_archived(),
+                    track.source().as_str(),
                 );
-                track_stmt.execute(values).unwrap();
+                track_stmt.execute(values)?;
             }
         }
-        tx.commit().unwrap();
+        tx.commit()?;
 
         // Insert artists and track_artists entries
         {
             for track in tracks {
                 for artist in track.artists() {
-                    let id = self.insert_artist(artist);
-                    self.con
-                        .execute(
-                            "INSERT OR IGNORE INTO track_artists (track_id, artist_id) VALUES (?1, ?2)",
-                            (*track.id(), id),
-                        )
-                        .unwrap();
+                    let id = self.insert_artist(artist)?;
+                    self.con.execute(
+                        "INSERT OR IGNORE INTO track_artists (track_id, artist_id) VALUES (?1, ?2)",
+                        (*track.id(), id),
+                    )?;
                 }
             }
         }
+        Ok(())
+    }
+
+    // Runs f in a transaction, which is rolled back if f or the commit fails. The files f
+    // changes through FileChanges are restored in that case.
+    fn in_transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self, &mut FileChanges) -> Result<T, ServerError>,
+    ) -> Result<T, ServerError> {
+        let mut files = FileChanges::default();
+        self.con.execute_batch("BEGIN")?;
+        let result = f(self, &mut files).and_then(|result| {
+            self.con.execute_batch("COMMIT")?;
+            Ok(result)
+        });
+        match result {
+            Ok(result) => {
+                files.commit();
+                Ok(result)
+            }
+            Err(e) => {
+                // Some failed commits already rolled the transaction back
+                if !self.con.is_autocommit() {
+                    self.con.execute_batch("ROLLBACK")?;
+                }
+                files.undo();
+                Err(e)
+            }
+        }
+    }
+
+    // Replaces the artists of the track, keeping their order
+    fn set_track_artists(&mut self, track: &Track) -> Result<(), ServerError> {
+        self.con.execute(
+            "DELETE FROM track_artists WHERE track_id = ?1",
+            [track.id()],
+        )?;
+        for artist in track.artists() {
+            let artist_id = self.insert_artist(artist)?;
+            self.con.execute(
+                "INSERT OR IGNORE INTO track_artists (track_id, artist_id) VALUES (?1, ?2)",
+                (track.id(), artist_id),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Inserts a newly archived track, unless its url is archived or trashed already. See
+    // update_track for before_commit.
+    pub fn insert_archived_track(
+        &mut self,
+        track: &Track,
+        before_commit: impl FnOnce(&mut FileChanges) -> Result<(), ServerError>,
+    ) -> Result<(), ServerError> {
+        self.in_transaction(|db, files| {
+            db.check_archivable(track.url())?;
+            db.con.execute(
+                "INSERT INTO tracks (id, url, title, date_archived, source)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                (
+                    track.id(),
+                    track.url(),
+                    track.title(),
+                    track.date_archived(),
+                    track.source().as_str(),
+                ),
+            )?;
+            db.set_track_artists(track)?;
+            before_commit(files)
+        })
+    }
+
+    // Updates title and artists of the track in a transaction. The transaction is only
+    // committed if before_commit succeeds, which changes the files through FileChanges so
+    // they stay in sync with the database.
+    pub fn update_track(
+        &mut self,
+        track: &Track,
+        before_commit: impl FnOnce(&mut FileChanges) -> Result<(), ServerError>,
+    ) -> Result<(), ServerError> {
+        self.in_transaction(|db, files| {
+            db.con.execute(
+                "UPDATE tracks SET title = ?2 WHERE id = ?1",
+                (track.id(), track.title()),
+            )?;
+            db.set_track_artists(track)?;
+            before_commit(files)
+        })
+    }
+
+    // Moves the track into the trash, see update_track for before_commit
+    pub fn trash_track(
+        &mut self,
+        track: &Track,
+        before_commit: impl FnOnce(&mut FileChanges) -> Result<(), ServerError>,
+    ) -> Result<(), ServerError> {
+        self.in_transaction(|db, files| {
+            db.con.execute(
+                "INSERT INTO trash (id, url, title, artists, date_archived, date_trashed, source)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                (
+                    track.id(),
+                    track.url(),
+                    track.title(),
+                    serde_json::to_string(track.artists()).unwrap(),
+                    track.date_archived(),
+                    Utc::now().naive_utc(),
+                    track.source().as_str(),
+                ),
+            )?;
+            db.con
+                .execute("DELETE FROM tracks WHERE id = ?1", [track.id()])?;
+            db.con.execute(
+                "DELETE FROM track_artists WHERE track_id = ?1",
+                [track.id()],
+            )?;
+            db.con.execute(
+                "DELETE FROM playlist_tracks WHERE track_id = ?1",
+                [track.id()],
+            )?;
+            before_commit(files)
+        })
+    }
+
+    // Moves the track out of the trash back into the library, see update_track for before_commit
+    pub fn restore_track(
+        &mut self,
+        id: u32,
+        before_commit: impl FnOnce(&mut FileChanges, &Track) -> Result<(), ServerError>,
+    ) -> Result<Track, ServerError> {
+        let Some(trashed) = self.trashed_track(id)? else {
+            return Err(not_in_trash(id));
+        };
+        self.in_transaction(|db, files| {
+            let track = trashed.track;
+            if db.is_track_archived(track.url())? {
+                return Err(url_archived_again(&track));
+            }
+            db.con.execute(
+                "INSERT INTO tracks (id, url, title, date_archived, source)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                (
+                    track.id(),
+                    track.url(),
+                    track.title(),
+                    track.date_archived(),
+                    track.source().as_str(),
+                ),
+            )?;
+            db.set_track_artists(&track)?;
+            db.con.execute("DELETE FROM trash WHERE id = ?1", [id])?;
+            before_commit(files, &track)?;
+            Ok(track)
+        })
+    }
+
+    // Deletes the track from the trash for good, see update_track for before_commit
+    pub fn purge_trashed_track(
+        &mut self,
+        id: u32,
+        before_commit: impl FnOnce(&mut FileChanges) -> Result<(), ServerError>,
+    ) -> Result<(), ServerError> {
+        self.in_transaction(|db, files| {
+            if db.con.execute("DELETE FROM trash WHERE id = ?1", [id])? == 0 {
+                return Err(not_in_trash(id));
+            }
+            before_commit(files)
+        })
+    }
+
+    pub fn trashed_track(&mut self, id: u32) -> Result<Option<TrashedTrack>, ServerError> {
+        let track = self
+            .con
+            .query_row(
+                "SELECT id, url, title, artists, date_archived, date_trashed, source FROM trash WHERE id = ?1",
+                [id],
+                trashed_track_from_row,
+            )
+            .optional()?;
+        Ok(track)
+    }
+
+    // Returns all trashed tracks, most recently trashed first
+    pub fn trashed_tracks(&mut self) -> Result<Vec<TrashedTrack>, ServerError> {
+        let mut sql = self.con.prepare(
+            "SELECT id, url, title, artists, date_archived, date_trashed, source FROM trash
+            ORDER BY date_trashed DESC",
+        )?;
+        let tracks = sql
+            .query_map([], trashed_track_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+
+    // Returns the ids of all tracks trashed before the given time
+    pub fn trashed_before(&mut self, time: NaiveDateTime) -> Result<Vec<u32>, ServerError> {
+        let mut sql = self
+            .con
+            .prepare("SELECT id FROM trash WHERE date_trashed < ?1")?;
+        let ids = sql
+            .query_map([time], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
     }
 
-    pub fn remove_tracks<'a>(&mut self, ids: impl Iterator<Item = u32> + Clone) {
-        let tx = self.con.transaction().unwrap();
+    pub fn remove_tracks(
+        &mut self,
+        ids: impl Iterator<Item = u32> + Clone,
+    ) -> Result<(), ServerError> {
+        let tx = self.con.transaction()?;
         {
             // Drop tracks
-            let mut stmt = tx.prepare("DELETE FROM tracks WHERE id = (?1)").unwrap();
+            let mut stmt = tx.prepare("DELETE FROM tracks WHERE id = (?1)")?;
             for id in ids.clone() {
-                stmt.execute([id]).unwrap();
+                stmt.execute([id])?;
             }
 
             // Drop track_artists
-            let mut stmt = tx
-                .prepare("DELETE FROM track_artists WHERE track_id = (?1)")
-                .unwrap();
+            let mut stmt = tx.prepare("DELETE FROM track_artists WHERE track_id = (?1)")?;
+            for id in ids.clone() {
+                stmt.execute([id])?;
+            }
+
+            // Drop tracks from playlists
+            let mut stmt = tx.prepare("DELETE FROM playlist_tracks WHERE track_id = (?1)")?;
             for id in ids {
-                stmt.execute([id]).unwrap();
+                stmt.execute([id])?;
             }
         }
-        tx.commit().unwrap();
+        tx.commit()?;
+
+        // Close the gaps the removed tracks left in playlists
+        for playlist in self.all_playlists()? {
+            self.set_playlist_track_ids(playlist.id, &playlist.track_ids)?;
+        }
+        Ok(())
     }
 
-    pub fn get_tracks<'a>(
+    // Returns the tracks in the order of ids, fails if any of them does not exist
+    pub fn get_tracks(
         &mut self,
         ids: impl Iterator<Item = u32> + Clone,
-    ) -> anyhow::Result<Vec<Track>> {
-        let mut tracks = Vec::new();
+    ) -> Result<Vec<Track>, ServerError> {
+        let ids = ids.collect::<Vec<_>>();
+        let tracks = self
+            .load_tracks(Some(&ids))?
+            .into_iter()
+            .map(|track| (track.id, track))
+            .collect::<HashMap<_, _>>();
+        ids.iter()
+            .map(|id| match tracks.get(id) {
+                Some(track) => Ok(track.clone()),
+                None => Err(track_not_found(*id)),
+            })
+            .collect()
+    }
 
-        // Get tracks
-        for id in ids.clone() {
-            let track = self.con.query_row(
-                "SELECT id, url, title, date_archived FROM tracks WHERE id = ?1",
-                [id],
-                |row| {
-                    let id = row.get(0).unwrap();
-                    let url = row.get(1).unwrap();
-                    let title = row.get(2).unwrap();
-                    let date_archived = row.get(3).unwrap();
-                    Ok(Track {
-                        id,
-                        url,
-                        title,
-                        artists: vec![],
-                        date_archived,
-                    })
-                },
-            )?;
-            tracks.push(track);
-        }
+    pub fn query_tracks(&mut self, query: &TrackQuery) -> Result<TrackPage, ServerError> {
+        // Escape LIKE wildcards so the query is matched literally
+        let pattern = format!(
+            "%{}%",
+            query
+                .query
+                .trim()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let filter = "
+            title LIKE ?1 ESCAPE '\\'
+            OR url LIKE ?1 ESCAPE '\\'
+            OR EXISTS(
+                SELECT 1 FROM track_artists
+                JOIN artists ON artists.id = track_artists.artist_id
+                WHERE track_artists.track_id = tracks.id AND artists.name LIKE ?1 ESCAPE '\\'
+            )";
+        let sort = match query.sort {
+            TrackSortField::Title => "title COLLATE NOCASE",
+            TrackSortField::FirstArtist => {
+                "(SELECT artists.name FROM track_artists
+                JOIN artists ON artists.id = track_artists.artist_id
+                WHERE track_artists.track_id = tracks.id
+                ORDER BY track_artists.rowid LIMIT 1) COLLATE NOCASE"
+            }
+            TrackSortField::DateArchived => "date_archived",
+            TrackSortField::Url => "url",
+        };
+        let direction = match query.direction {
+            SortDirection::Ascending => "ASC",
+            SortDirection::Descending => "DESC",
+        };
 
-        // Get artists
-        let mut sql = self
-            .con
-            .prepare(
-                "
-            SELECT
-                artists.name AS artist_name
-            FROM
-                artists
-            JOIN
-                track_artists ON artists.id = track_artists.artist_id
-            WHERE
-                track_artists.track_id = ?1;
-        ",
-            )
-            .unwrap();
+        let total: u32 = self.con.query_row(
+            &format!("SELECT COUNT(*) FROM tracks WHERE ({filter})"),
+            [&pattern],
+            |r| r.get(0),
+        )?;
+
+        let ids = {
+            let mut sql = self.con.prepare(&format!(
+                "SELECT id FROM tracks WHERE ({filter}) ORDER BY {sort} {direction}, id ASC LIMIT ?2 OFFSET ?3"
+            ))?;
+            let ids = sql
+                .query_map(
+                    (
+                        &pattern,
+                        query.limit.min(TrackQuery::MAX_LIMIT),
+                        query.offset,
+                    ),
+                    |r| r.get::<_, u32>(0),
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            ids
+        };
+
+        let tracks = self.get_tracks(ids.into_iter())?;
+        Ok(TrackPage { tracks, total })
+    }
+
+    pub fn is_track_archived(&self, url: &str) -> Result<bool, ServerError> {
+        Ok(self.archived_track_id(url)?.is_some())
+    }
 
-        for track in &mut tracks {
-            let artists = sql
-                .query_map([track.id], |row| {
-                    let name: String = row.get_unwrap(0);
-                    Ok(name)
-                })?
-                .map(|a| a.unwrap());
-            track.artists.extend(artists)
+    // Fails with a conflict if the url is archived, or in the trash where it can be restored
+    pub fn check_archivable(&self, url: &str) -> Result<(), ServerError> {
+        if self.is_track_archived(url)? {
+            return Err(ServerError::Conflict(
+                "Track is already archived".to_string(),
+            ));
         }
-        Ok(tracks)
+        if let Some(id) = self.trashed_track_id(url)? {
+            return Err(ServerError::Conflict(format!(
+                "Track is in the trash as track {id}, restore it instead"
+            )));
+        }
+        Ok(())
     }
 
-    pub fn is_track_archived(&self, url: &str) -> bool {
-        self.con
-            .query_row(
-                "SELECT EXISTS(SELECT 1 FROM tracks WHERE url = ?1)",
-                [url],
-                |v| v.get(0),
-            )
-            .unwrap()
+    // Returns the id of the trashed track archived from the url
+    pub fn trashed_track_id(&self, url: &str) -> Result<Option<u32>, ServerError> {
+        let id = self
+            .con
+            .query_row("SELECT id FROM trash WHERE url = ?1", [url], |v| v.get(0))
+            .optional()?;
+        Ok(id)
+    }
+
+    // Returns the id of the track archived from the url
+    pub fn archived_track_id(&self, url: &str) -> Result<Option<u32>, ServerError> {
+        let id = self
+            .con
+            .query_row("SELECT id FROM tracks WHERE url = ?1", [url], |v| v.get(0))
+            .optional()?;
+        Ok(id)
     }
 
-    pub fn artist_id(&mut self, artist: &str) -> Option<u32> {
-        self.con
+    pub fn artist_id(&mut self, artist: &str) -> Result<Option<u32>, ServerError> {
+        let id = self
+            .con
             .query_row(
                 "SELECT id FROM artists WHERE name = ?1 COLLATE NOCASE",
                 [artist],
                 |r| r.get(0),
             )
-            .optional()
-            .unwrap()
+            .optional()?;
+        Ok(id)
     }
 
     // Inserts artist and returns id of artist
-    pub fn insert_artist(&mut self, artist: &str) -> u32 {
-        match self.artist_id(artist) {
-            Some(id) => id,
+    pub fn insert_artist(&mut self, artist: &str) -> Result<u32, ServerError> {
+        match self.artist_id(artist)? {
+            Some(id) => Ok(id),
             None => {
                 let id = self.next_artist_id();
-                self.con
-                    .execute(
-                        "INSERT INTO artists (id, name) VALUES (?1, ?2)",
-                        (id, artist),
-                    )
-                    .unwrap();
-                id
+                self.con.execute(
+                    "INSERT INTO artists (id, name) VALUES (?1, ?2)",
+                    (id, artist),
+                )?;
+                Ok(id)
             }
         }
     }
 
-    pub fn all_tracks(&mut self) -> Vec<Track> {
-        // Get tracks WITHOUT artists
-        let mut sql = self
-            .con
-            .prepare("SELECT id, url, title, date_archived FROM tracks")
-            .unwrap();
+    pub fn all_tracks(&mut self) -> Result<Vec<Track>, ServerError> {
+        Ok(self.load_tracks(None)?)
+    }
 
-        let mut tracks = sql
-            .query_map([], |row| {
-                let id: u32 = row.get(0).unwrap();
-                let url: String = row.get(1).unwrap();
-                let title: String = row.get(2).unwrap();
-                let date_archived: NaiveDate = row.get(3).unwrap();
+    // Loads the tracks with the ids, or all tracks if None, ordered by id. Instead of one
+    // artist query per track, all tracks and then all of their artists are read at once.
+    fn load_tracks(&self, ids: Option<&[u32]>) -> rusqlite::Result<Vec<Track>> {
+        let (track_filter, artist_filter, params) = match ids {
+            Some(ids) => (
+                "WHERE id IN (SELECT value FROM json_each(?1))",
+                "WHERE track_artists.track_id IN (SELECT value FROM json_each(?1))",
+                vec![serde_json::to_string(ids).unwrap()],
+            ),
+            None => ("", "", vec![]),
+        };
 
+        let mut sql = self.con.prepare(&format!(
+            "SELECT id, url, title, date_archived, source FROM tracks {track_filter} ORDER BY id"
+        ))?;
+        let mut tracks = sql
+            .query_map(rusqlite::params_from_iter(&params), |row| {
+                let source: String = row.get(4)?;
                 Ok(Track {
-                    id,
-                    url,
-                    title,
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    source: SourceKind::parse(&source).unwrap_or_default(),
+                    title: row.get(2)?,
                     artists: vec![],
-                    date_archived,
+                    date_archived: row.get(3)?,
                 })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let positions = tracks
+            .iter()
+            .enumerate()
+            .map(|(position, track)| (track.id, position))
+            .collect::<HashMap<_, _>>();
+
+        // The rowid is the order the artists of a track were added in
+        let mut sql = self.con.prepare(&format!(
+            "SELECT track_artists.track_id, artists.name FROM track_artists
+            JOIN artists ON artists.id = track_artists.artist_id
+            {artist_filter}
+            ORDER BY track_artists.rowid"
+        ))?;
+        let mut rows = sql.query(rusqlite::params_from_iter(&params))?;
+        while let Some(row) = rows.next()? {
+            let track_id: u32 = row.get(0)?;
+            if let Some(&position) = positions.get(&track_id) {
+                tracks[position].artists.push(row.get(1)?);
+            }
+        }
+        Ok(tracks)
+    }
+
+    // Queues a new archive job and returns its id
+    // The archived track is added to the playlist if one is given, at the position of the
+    // track in the collection the playlist was imported from
+    pub fn insert_archive_job(
+        &mut self,
+        candidate: &Candidate,
+        playlist: Option<(u32, u32)>,
+    ) -> Result<u32, ServerError> {
+        let id = self.next_archive_job_id();
+        let now = Utc::now().naive_utc();
+        self.con.execute(
+            "INSERT INTO archive_jobs (id, url, title, artists, status, date_created, date_updated, playlist_id, playlist_position)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, ?7, ?8)",
+            (
+                id,
+                &candidate.url,
+                &candidate.title,
+                serde_json::to_string(&candidate.artists).unwrap(),
+                ArchiveJobStatus::Queued.as_str(),
+                now,
+                playlist.map(|(playlist_id, _)| playlist_id),
+                playlist.map(|(_, position)| position),
+            ),
+        )?;
+        Ok(id)
+    }
+
+    pub fn set_archive_job_status(
+        &mut self,
+        id: u32,
+        status: ArchiveJobStatus,
+        error: Option<&str>,
+        track_id: Option<u32>,
+    ) -> Result<(), ServerError> {
+        self.con.execute(
+            "UPDATE archive_jobs SET status = ?2, error = ?3, track_id = ?4, date_updated = ?5 WHERE id = ?1",
+            (
+                id,
+                status.as_str(),
+                error,
+                track_id,
+                Utc::now().naive_utc(),
+            ),
+        )?;
+        Ok(())
+    }
+
+    // Puts jobs that were interrupted (e.g. by a restart) back into the queue
+    pub fn requeue_running_archive_jobs(&mut self) -> Result<(), ServerError> {
+        self.con.execute(
+            "UPDATE archive_jobs SET status = ?1 WHERE status = ?2",
+            (
+                ArchiveJobStatus::Queued.as_str(),
+                ArchiveJobStatus::Running.as_str(),
+            ),
+        )?;
+        Ok(())
+    }
+
+    // Returns the oldest queued archive job
+    pub fn next_queued_archive_job(&mut self) -> Result<Option<ArchiveJob>, ServerError> {
+        let job = self
+            .con
+            .query_row(
+                "SELECT id, url, title, artists, status, error, track_id, date_created, date_updated, playlist_id,
+                playlist_position
+                FROM archive_jobs WHERE status = ?1 ORDER BY id ASC LIMIT 1",
+                [ArchiveJobStatus::Queued.as_str()],
+                archive_job_from_row,
+            )
+            .optional()?;
+        Ok(job)
+    }
+
+    pub fn archive_job(&mut self, id: u32) -> Result<Option<ArchiveJob>, ServerError> {
+        let job = self
+            .con
+            .query_row(
+                "SELECT id, url, title, artists, status, error, track_id, date_created, date_updated, playlist_id,
+                playlist_position
+                FROM archive_jobs WHERE id = ?1",
+                [id],
+                archive_job_from_row,
+            )
+            .optional()?;
+        Ok(job)
+    }
+
+    // Returns all archive jobs, newest first
+    pub fn all_archive_jobs(&mut self) -> Result<Vec<ArchiveJob>, ServerError> {
+        let mut sql = self.con.prepare(
+            "SELECT id, url, title, artists, status, error, track_id, date_created, date_updated, playlist_id,
+                playlist_position
+            FROM archive_jobs ORDER BY id DESC",
+        )?;
+        let jobs = sql
+            .query_map([], archive_job_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(jobs)
+    }
+
+    // Creates an empty playlist at the end of the playlist order and returns its id
+    pub fn create_playlist(&mut self, name: &str) -> Result<u32, ServerError> {
+        let id = self.next_playlist_id();
+        self.con.execute(
+            "INSERT INTO playlists (id, name, position)
+            VALUES (?1, ?2, (SELECT IFNULL(MAX(position) + 1, 0) FROM playlists))",
+            (id, name),
+        )?;
+        Ok(id)
+    }
+
+    pub fn rename_playlist(&mut self, id: u32, name: &str) -> Result<(), ServerError> {
+        if self
+            .con
+            .execute("UPDATE playlists SET name = ?2 WHERE id = ?1", (id, name))?
+            == 0
+        {
+            return Err(playlist_not_found(id));
+        }
+        Ok(())
+    }
+
+    pub fn delete_playlist(&mut self, id: u32) -> Result<(), ServerError> {
+        let tx = self.con.transaction()?;
+        tx.execute("DELETE FROM playlist_tracks WHERE playlist_id = ?1", [id])?;
+        let deleted = tx.execute("DELETE FROM playlists WHERE id = ?1", [id])?;
+        if deleted == 0 {
+            return Err(playlist_not_found(id));
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Reorders the playlists, ids must contain every playlist exactly once
+    pub fn order_playlists(&mut self, ids: &[u32]) -> Result<(), ServerError> {
+        let mut current = self
+            .all_playlists()?
+            .into_iter()
+            .map(|p| p.id)
+            .collect::<Vec<_>>();
+        let mut new = ids.to_vec();
+        current.sort_unstable();
+        new.sort_unstable();
+        if current != new {
+            return Err(ServerError::BadRequest(
+                "The new order has to contain every playlist exactly once".to_string(),
+            ));
+        }
+
+        let tx = self.con.transaction()?;
+        {
+            let mut stmt = tx.prepare("UPDATE playlists SET position = ?2 WHERE id = ?1")?;
+            for (position, id) in ids.iter().enumerate() {
+                stmt.execute((id, position as u32))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn playlist(&mut self, id: u32) -> Result<Option<Playlist>, ServerError> {
+        let name: Option<String> = self
+            .con
+            .query_row("SELECT name FROM playlists WHERE id = ?1", [id], |r| {
+                r.get(0)
             })
-            .unwrap()
-            .map(|track| track.expect("Expected all tracks read from database to be valid."))
+            .optional()?;
+        let Some(name) = name else {
+            return Ok(None);
+        };
+        let track_ids = self.playlist_track_ids(id)?;
+        Ok(Some(Playlist {
+            id,
+            name,
+            track_ids,
+        }))
+    }
+
+    // Returns all playlists in their order
+    pub fn all_playlists(&mut self) -> Result<Vec<Playlist>, ServerError> {
+        let mut sql = self
+            .con
+            .prepare("SELECT id, name FROM playlists ORDER BY position ASC")?;
+        let mut playlists = sql
+            .query_map([], |row| {
+                Ok(Playlist {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    track_ids: vec![],
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(sql);
+
+        for playlist in &mut playlists {
+            playlist.track_ids = self.playlist_track_ids(playlist.id)?;
+        }
+        Ok(playlists)
+    }
+
+    // Appends the tracks to the end of the playlist
+    pub fn add_playlist_tracks(&mut self, id: u32, track_ids: &[u32]) -> Result<(), ServerError> {
+        let Some(playlist) = self.playlist(id)? else {
+            return Err(playlist_not_found(id));
+        };
+        for track_id in track_ids {
+            if !self.track_exists(*track_id)? {
+                return Err(track_not_found(*track_id));
+            }
+        }
+        let mut new = playlist.track_ids;
+        new.extend_from_slice(track_ids);
+        self.set_playlist_track_ids(id, &new)
+    }
+
+    // Inserts the track of an archive job at its position in the collection the playlist was
+    // imported from. The position counts the tracks of earlier jobs, which may be archived
+    // later or never, so those which are not in the playlist yet are skipped.
+    pub fn insert_imported_playlist_track(
+        &mut self,
+        id: u32,
+        position: u32,
+        track_id: u32,
+    ) -> Result<(), ServerError> {
+        let Some(playlist) = self.playlist(id)? else {
+            return Err(playlist_not_found(id));
+        };
+        let mut track_ids = playlist.track_ids;
+        let mut sql = self.con.prepare(
+            "SELECT track_id FROM archive_jobs WHERE playlist_id = ?1 AND playlist_position < ?2",
+        )?;
+        let missing = sql
+            .query_map((id, position), |row| row.get::<_, Option<u32>>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|earlier| !earlier.is_some_and(|earlier| track_ids.contains(&earlier)))
+            .count();
+        drop(sql);
+        // The playlist may have been edited in the meantime
+        let index = (position as usize)
+            .saturating_sub(missing)
+            .min(track_ids.len());
+        track_ids.insert(index, track_id);
+        self.set_playlist_track_ids(id, &track_ids)
+    }
+
+    // Removes every occurrence of the tracks from the playlist
+    pub fn remove_playlist_tracks(
+        &mut self,
+        id: u32,
+        track_ids: &[u32],
+    ) -> Result<(), ServerError> {
+        let Some(playlist) = self.playlist(id)? else {
+            return Err(playlist_not_found(id));
+        };
+        let new = playlist
+            .track_ids
+            .into_iter()
+            .filter(|track_id| !track_ids.contains(track_id))
             .collect::<Vec<_>>();
+        self.set_playlist_track_ids(id, &new)
+    }
+
+    // Reorders the tracks of the playlist, track_ids must be a permutation of the current tracks
+    pub fn order_playlist_tracks(&mut self, id: u32, track_ids: &[u32]) -> Result<(), ServerError> {
+        let Some(playlist) = self.playlist(id)? else {
+            return Err(playlist_not_found(id));
+        };
+        let mut current = playlist.track_ids;
+        let mut new = track_ids.to_vec();
+        current.sort_unstable();
+        new.sort_unstable();
+        if current != new {
+            return Err(ServerError::BadRequest(
+                "The new order has to contain exactly the tracks of the playlist".to_string(),
+            ));
+        }
+        self.set_playlist_track_ids(id, track_ids)
+    }
 
-        // Get artists
+    fn playlist_track_ids(&self, id: u32) -> Result<Vec<u32>, ServerError> {
+        let mut sql = self.con.prepare(
+            "SELECT track_id FROM playlist_tracks WHERE playlist_id = ?1 ORDER BY position ASC",
+        )?;
+        let track_ids = sql
+            .query_map([id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(track_ids)
+    }
+
+    fn set_playlist_track_ids(&mut self, id: u32, track_ids: &[u32]) -> Result<(), ServerError> {
+        let tx = self.con.transaction()?;
+        tx.execute("DELETE FROM playlist_tracks WHERE playlist_id = ?1", [id])?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO playlist_tracks (playlist_id, position, track_id) VALUES (?1, ?2, ?3)",
+            )?;
+            for (position, track_id) in track_ids.iter().enumerate() {
+                stmt.execute((id, position as u32, track_id))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn track_exists(&self, id: u32) -> Result<bool, ServerError> {
+        let exists = self.con.query_row(
+            "SELECT EXISTS(SELECT 1 FROM tracks WHERE id = ?1)",
+            [id],
+            |v| v.get(0),
+        )?;
+        Ok(exists)
+    }
+
+    // Fails if a user with the same name, ignoring case, already exists
+    pub fn insert_user(
+        &mut self,
+        user: &NewUser,
+        password_hash: &str,
+    ) -> Result<User, ServerError> {
+        if self.user_credentials(&user.name)?.is_some() {
+            return Err(ServerError::Conflict(format!(
+                "A user named {} already exists",
+                user.name
+            )));
+        }
+        let id = self.next_user_id();
+        self.con.execute(
+            "INSERT INTO users (id, name, password_hash, role) VALUES (?1, ?2, ?3, ?4)",
+            (id, &user.name, password_hash, user.role.as_str()),
+        )?;
+        Ok(User {
+            id,
+            name: user.name.clone(),
+            role: user.role,
+        })
+    }
+
+    pub fn user(&mut self, id: u32) -> Result<Option<User>, ServerError> {
+        let user = self
+            .con
+            .query_row(
+                "SELECT id, name, role FROM users WHERE id = ?1",
+                [id],
+                user_from_row,
+            )
+            .optional()?;
+        Ok(user)
+    }
+
+    // Returns the user together with their password hash, the name is matched ignoring case
+    pub fn user_credentials(&mut self, name: &str) -> Result<Option<(User, String)>, ServerError> {
+        let credentials = self
+            .con
+            .query_row(
+                "SELECT id, name, role, password_hash FROM users WHERE name = ?1",
+                [name],
+                |row| Ok((user_from_row(row)?, row.get(3)?)),
+            )
+            .optional()?;
+        Ok(credentials)
+    }
+
+    pub fn all_users(&mut self) -> Result<Vec<User>, ServerError> {
         let mut sql = self
             .con
-            .prepare(
-                "
-            SELECT
-                artists.name AS artist_name
-            FROM
-                artists
-            JOIN
-                track_artists ON artists.id = track_artists.artist_id
-            WHERE
-                track_artists.track_id = ?1;
-        ",
+            .prepare("SELECT id, name, role FROM users ORDER BY id ASC")?;
+        let users = sql
+            .query_map([], user_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(users)
+    }
+
+    // Fails if the user does not exist or is the last admin losing the role
+    pub fn set_user_role(&mut self, id: u32, role: Role) -> Result<(), ServerError> {
+        let Some(user) = self.user(id)? else {
+            return Err(user_not_found(id));
+        };
+        if user.role == Role::Admin && role != Role::Admin && self.admin_count()? == 1 {
+            return Err(ServerError::Conflict(
+                "The last admin can not lose the admin role".to_string(),
+            ));
+        }
+        self.con.execute(
+            "UPDATE users SET role = ?2 WHERE id = ?1",
+            (id, role.as_str()),
+        )?;
+        Ok(())
+    }
+
+    // Revokes the sessions and access tokens of the user together with the old password, except
+    // the session or access token the user changes the password with
+    pub fn set_user_password(
+        &mut self,
+        id: u32,
+        password_hash: &str,
+        keep_session: Option<u32>,
+        keep_access_token: Option<u32>,
+    ) -> Result<(), ServerError> {
+        let tx = self.con.transaction()?;
+        if tx.execute(
+            "UPDATE users SET password_hash = ?2 WHERE id = ?1",
+            (id, password_hash),
+        )? == 0
+        {
+            return Err(user_not_found(id));
+        }
+        tx.execute(
+            "DELETE FROM sessions WHERE user_id = ?1 AND id IS NOT ?2",
+            (id, keep_session),
+        )?;
+        tx.execute(
+            "DELETE FROM access_tokens WHERE user_id = ?1 AND id IS NOT ?2",
+            (id, keep_access_token),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Fails if the user does not exist or is the last admin
+    pub fn delete_user(&mut self, id: u32) -> Result<(), ServerError> {
+        let Some(user) = self.user(id)? else {
+            return Err(user_not_found(id));
+        };
+        if user.role == Role::Admin && self.admin_count()? == 1 {
+            return Err(ServerError::Conflict(
+                "The last admin can not be deleted".to_string(),
+            ));
+        }
+        self.in_transaction(|db, _| {
+            db.con.execute("DELETE FROM users WHERE id = ?1", [id])?;
+            db.con
+                .execute("DELETE FROM sessions WHERE user_id = ?1", [id])?;
+            db.con
+                .execute("DELETE FROM access_tokens WHERE user_id = ?1", [id])?;
+            Ok(())
+        })
+    }
+
+    pub fn insert_session(
+        &mut self,
+        token_hash: &str,
+        user_id: u32,
+    ) -> Result<Session, ServerError> {
+        let id = self.next_session_id();
+        let now = Utc::now().naive_utc();
+        self.con.execute(
+            "INSERT INTO sessions (id, token_hash, user_id, date_created, date_last_seen)
+            VALUES (?1, ?2, ?3, ?4, ?4)",
+            (id, token_hash, user_id, now),
+        )?;
+        Ok(Session {
+            id,
+            user_id,
+            date_created: now,
+            date_last_seen: now,
+        })
+    }
+
+    pub fn session_by_token_hash(
+        &mut self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, ServerError> {
+        let session = self
+            .con
+            .query_row(
+                "SELECT id, user_id, date_created, date_last_seen FROM sessions WHERE token_hash = ?1",
+                [token_hash],
+                session_from_row,
             )
+            .optional()?;
+        Ok(session)
+    }
+
+    pub fn session(&mut self, id: u32) -> Result<Option<Session>, ServerError> {
+        let session = self
+            .con
+            .query_row(
+                "SELECT id, user_id, date_created, date_last_seen FROM sessions WHERE id = ?1",
+                [id],
+                session_from_row,
+            )
+            .optional()?;
+        Ok(session)
+    }
+
+    // Returns the sessions of the user or of all users if None, most recently seen first
+    pub fn sessions(&mut self, user_id: Option<u32>) -> Result<Vec<Session>, ServerError> {
+        let mut sql = self.con.prepare(
+            "SELECT id, user_id, date_created, date_last_seen FROM sessions
+            WHERE ?1 IS NULL OR user_id = ?1 ORDER BY date_last_seen DESC",
+        )?;
+        let sessions = sql
+            .query_map([user_id], session_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(sessions)
+    }
+
+    pub fn touch_session(&mut self, id: u32, time: NaiveDateTime) -> Result<(), ServerError> {
+        self.con.execute(
+            "UPDATE sessions SET date_last_seen = ?2 WHERE id = ?1",
+            (id, time),
+        )?;
+        Ok(())
+    }
+
+    // Returns false if the session does not exist
+    pub fn delete_session(&mut self, id: u32) -> Result<bool, ServerError> {
+        Ok(self
+            .con
+            .execute("DELETE FROM sessions WHERE id = ?1", [id])?
+            > 0)
+    }
+
+    pub fn insert_access_token(
+        &mut self,
+        token_hash: &str,
+        user_id: u32,
+        new: &NewAccessToken,
+    ) -> Result<AccessToken, ServerError> {
+        let token = AccessToken {
+            id: self.next_access_token_id(),
+            user_id,
+            name: new.name.clone(),
+            scopes: new.scopes.clone(),
+            date_created: Utc::now().naive_utc(),
+            date_expires: new.date_expires,
+            date_last_used: None,
+        };
+        let scopes = token.scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        self.con.execute(
+            "INSERT INTO access_tokens (id, token_hash, user_id, name, scopes, date_created, date_expires)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            (
+                token.id,
+                token_hash,
+                user_id,
+                &token.name,
+                serde_json::to_string(&scopes).unwrap(),
+                token.date_created,
+                token.date_expires,
+            ),
+        )?;
+        Ok(token)
+    }
+
+    pub fn access_token_by_hash(
+        &mut self,
+        token_hash: &str,
+    ) -> Result<Option<AccessToken>, ServerError> {
+        let token = self
+            .con
+            .query_row(
+                "SELECT id, user_id, name, scopes, date_created, date_expires, date_last_used
+                FROM access_tokens WHERE token_hash = ?1",
+                [token_hash],
+                access_token_from_row,
+            )
+            .optional()?;
+        Ok(token)
+    }
+
+    pub fn access_token(&mut self, id: u32) -> Result<Option<AccessToken>, ServerError> {
+        let token = self
+            .con
+            .query_row(
+                "SELECT id, user_id, name, scopes, date_created, date_expires, date_last_used
+                FROM access_tokens WHERE id = ?1",
+                [id],
+                access_token_from_row,
+            )
+            .optional()?;
+        Ok(token)
+    }
+
+    // Returns the access tokens of the user or of all users if None, newest first
+    pub fn access_tokens(&mut self, user_id: Option<u32>) -> Result<Vec<AccessToken>, ServerError> {
+        let mut sql = self.con.prepare(
+            "SELECT id, user_id, name, scopes, date_created, date_expires, date_last_used
+            FROM access_tokens WHERE ?1 IS NULL OR user_id = ?1 ORDER BY id DESC",
+        )?;
+        let tokens = sql
+            .query_map([user_id], access_token_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tokens)
+    }
+
+    pub fn touch_access_token(&mut self, id: u32, time: NaiveDateTime) -> Result<(), ServerError> {
+        self.con.execute(
+            "UPDATE access_tokens SET date_last_used = ?2 WHERE id = ?1",
+            (id, time),
+        )?;
+        Ok(())
+    }
+
+    // Returns false if the access token does not exist
+    pub fn delete_access_token(&mut self, id: u32) -> Result<bool, ServerError> {
+        Ok(self
+            .con
+            .execute("DELETE FROM access_tokens WHERE id = ?1", [id])?
+            > 0)
+    }
+
+    // Deletes sessions last seen or created before the given times and returns how many
+    pub fn delete_expired_sessions(
+        &mut self,
+        last_seen_before: NaiveDateTime,
+        created_before: NaiveDateTime,
+    ) -> Result<usize, ServerError> {
+        let deleted = self.con.execute(
+            "DELETE FROM sessions WHERE date_last_seen < ?1 OR date_created < ?2",
+            (last_seen_before, created_before),
+        )?;
+        Ok(deleted)
+    }
+
+    fn admin_count(&self) -> Result<u32, ServerError> {
+        let count = self.con.query_row(
+            "SELECT COUNT(*) FROM users WHERE role = ?1",
+            [Role::Admin.as_str()],
+            |v| v.get(0),
+        )?;
+        Ok(count)
+    }
+}
+
+/// File changes made together with a transaction. Renames are undone if the transaction is
+/// rolled back, removed files are only deleted once it is committed.
+#[derive(Default)]
+pub struct FileChanges {
+    renamed: Vec<(PathBuf, PathBuf)>,
+    removed: Vec<PathBuf>,
+}
+
+impl FileChanges {
+    pub fn rename(&mut self, from: &Path, to: &Path) -> Result<(), ServerError> {
+        std::fs::rename(from, to)?;
+        self.renamed.push((from.to_path_buf(), to.to_path_buf()));
+        Ok(())
+    }
+
+    // Moves the file aside until the transaction is committed
+    pub fn remove(&mut self, path: &Path) -> Result<(), ServerError> {
+        let mut aside = path.to_path_buf();
+        aside.set_file_name(format!(
+            ".{}.removed",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        self.rename(path, &aside)?;
+        self.removed.push(aside);
+        Ok(())
+    }
+
+    fn commit(self) {
+        for path in self.removed {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Unable to remove {path:?}: {e}");
+            }
+        }
+    }
+
+    fn undo(self) {
+        for (from, to) in self.renamed.into_iter().rev() {
+            if let Err(e) = std::fs::rename(&to, &from) {
+                error!("Unable to move {to:?} back to {from:?}: {e}");
+            }
+        }
+    }
+}
+
+fn track_not_found(id: u32) -> ServerError {
+    ServerError::NotFound(format!("No track with id {id} exists"))
+}
+
+fn not_in_trash(id: u32) -> ServerError {
+    ServerError::NotFound(format!("No track with id {id} is in the trash"))
+}
+
+// The url of the trashed track was archived again since it was trashed
+pub fn url_archived_again(track: &Track) -> ServerError {
+    ServerError::Conflict(format!(
+        "Track {} can not be restored, its url was archived again",
+        track.id()
+    ))
+}
+
+fn playlist_not_found(id: u32) -> ServerError {
+    ServerError::NotFound(format!("No playlist with id {id} exists"))
+}
+
+fn user_not_found(id: u32) -> ServerError {
+    ServerError::NotFound(format!("No user with id {id} exists"))
+}
+
+// Returns the highest id the query selects, 0 if it selects none
+fn max_id(con: &Connection, ids: &str) -> rusqlite::Result<u32> {
+    con.query_row(
+        &format!("SELECT IFNULL(MAX(id), 0) FROM ({ids})"),
+        [],
+        |row| row.get(0),
+    )
+}
+
+fn access_token_from_row(row: &rusqlite::Row) -> rusqlite::Result<AccessToken> {
+    let scopes: String = row.get(3)?;
+    let scopes: Vec<String> = serde_json::from_str(&scopes).unwrap_or_default();
+    Ok(AccessToken {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        // Unknown scopes grant nothing
+        scopes: scopes.iter().filter_map(|s| Scope::parse(s)).collect(),
+        date_created: row.get(4)?,
+        date_expires: row.get(5)?,
+        date_last_used: row.get(6)?,
+    })
+}
+
+fn session_from_row(row: &rusqlite::Row) -> rusqlite::Result<Session> {
+    Ok(Session {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        date_created: row.get(2)?,
+        date_last_seen: row.get(3)?,
+    })
+}
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    let role: String = row.get(2)?;
+    Ok(User {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        // Unknown roles get the least permissions
+        role: Role::parse(&role).unwrap_or(Role::Listener),
+    })
+}
+
+fn trashed_track_from_row(row: &rusqlite::Row) -> rusqlite::Result<TrashedTrack> {
+    let artists: String = row.get(3)?;
+    let source: String = row.get(6)?;
+    Ok(TrashedTrack {
+        track: Track {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            source: SourceKind::parse(&source).unwrap_or_default(),
+            title: row.get(2)?,
+            artists: serde_json::from_str(&artists).unwrap_or_default(),
+            date_archived: row.get(4)?,
+        },
+        date_trashed: row.get(5)?,
+    })
+}
+
+fn archive_job_from_row(row: &rusqlite::Row) -> rusqlite::Result<ArchiveJob> {
+    let artists: String = row.get(3)?;
+    let status: String = row.get(4)?;
+    let date_created: NaiveDateTime = row.get(7)?;
+    let date_updated: NaiveDateTime = row.get(8)?;
+    Ok(ArchiveJob {
+        id: row.get(0)?,
+        candidate: Candidate {
+            url: row.get(1)?,
+            title: row.get(2)?,
+            artists: serde_json::from_str(&artists).unwrap_or_default(),
+        },
+        status: ArchiveJobStatus::parse(&status).unwrap_or(ArchiveJobStatus::Failed),
+        error: row.get(5)?,
+        track_id: row.get(6)?,
+        playlist_id: row.get(9)?,
+        playlist_position: row.get(10)?,
+        date_created,
+        date_updated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // The directory is removed when the TempDir is dropped, also if the test fails
+    fn temp_database() -> (Database, TempDir) {
+        let dir = TempDir::new().unwrap();
+        (Database::new(dir.path().to_path_buf()).unwrap(), dir)
+    }
+
+    #[test]
+    fn file_changes_follow_the_transaction() {
+        let (mut db, dir) = temp_database();
+        let old = dir.path().join("old.m4a");
+        let new = dir.path().join("new.m4a");
+        let removed = dir.path().join("removed.m4a");
+        std::fs::write(&old, "old").unwrap();
+        std::fs::write(&removed, "removed").unwrap();
+
+        let result = db.in_transaction(|_, files| {
+            files.rename(&old, &new)?;
+            files.remove(&removed)?;
+            Err::<(), _>(ServerError::Conflict("rolled back".to_string()))
+        });
+        assert!(result.is_err());
+        assert!(old.exists() && !new.exists() && removed.exists());
+
+        db.in_transaction(|_, files| {
+            files.rename(&old, &new)?;
+            files.remove(&removed)
+        })
+        .unwrap();
+        assert!(!old.exists() && new.exists() && !removed.exists());
+        let leftovers = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(".removed")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn archived_urls_are_not_archived_again() {
+        let (mut db, dir) = temp_database();
+        let track = |id| {
+            Track::new(
+                id,
+                "youtu.be/dQw4w9WgXcQ".to_string(),
+                SourceKind::YouTube,
+                format!("Track {id}"),
+                vec!["Artist".to_string()],
+                chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            )
+        };
+        let downloaded = dir.path().join("downloaded.m4a");
+        let archived = dir.path().join("archived.m4a");
+        std::fs::write(&downloaded, "track").unwrap();
+
+        db.insert_archived_track(&track(1), |files| files.rename(&downloaded, &archived))
             .unwrap();
+        assert!(!downloaded.exists() && archived.exists());
+        assert_eq!(db.all_tracks().unwrap(), [track(1)]);
 
-        for track in &mut tracks {
-            let artists = sql
-                .query_map([track.id], |row| {
-                    let name: String = row.get_unwrap(0);
-                    Ok(name)
-                })
-                .unwrap()
-                .map(|a| a.unwrap());
-            track.artists.extend(artists)
+        std::fs::write(&downloaded, "track").unwrap();
+        let result = db.insert_archived_track(&track(2), |files| {
+            files.rename(&downloaded, &dir.path().join("again.m4a"))
+        });
+        assert!(matches!(result, Err(ServerError::Conflict(_))));
+        assert!(downloaded.exists());
+        assert_eq!(db.all_tracks().unwrap(), [track(1)]);
+    }
+
+    #[test]
+    fn trashed_urls_are_restored_instead_of_archived_again() {
+        let (mut db, _dir) = temp_database();
+        let track = |id| {
+            Track::new(
+                id,
+                "youtu.be/dQw4w9WgXcQ".to_string(),
+                SourceKind::YouTube,
+                format!("Track {id}"),
+                Vec::new(),
+                chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            )
+        };
+        db.insert_archived_track(&track(1), |_| Ok(())).unwrap();
+        db.trash_track(&track(1), |_| Ok(())).unwrap();
+
+        let result = db.insert_archived_track(&track(2), |_| Ok(()));
+        assert!(matches!(result, Err(ServerError::Conflict(_))));
+
+        // Archived again before archiving checked the trash
+        db.insert_tracks([track(2)].iter()).unwrap();
+        let result = db.restore_track(1, |_, _| Ok(()));
+        assert!(matches!(result, Err(ServerError::Conflict(_))));
+        assert!(db.trashed_track(1).unwrap().is_some());
+    }
+
+    #[test]
+    fn imported_tracks_keep_the_order_of_the_collection() {
+        let (mut db, _dir) = temp_database();
+        let tracks = (1..=4)
+            .map(|id| {
+                Track::new(
+                    id,
+                    format!("youtu.be/{id:0>11}"),
+                    SourceKind::YouTube,
+                    format!("Track {id}"),
+                    Vec::new(),
+                    chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        // The collection is 2, 3, 1, 4, where only track 1 was archived before the import
+        db.insert_tracks(tracks[..1].iter()).unwrap();
+        let playlist = db.create_playlist("import").unwrap();
+        db.add_playlist_tracks(playlist, &[1]).unwrap();
+        let mut jobs = Vec::new();
+        for (track, position) in [(2, 0), (3, 1), (4, 3)] {
+            let candidate = Candidate {
+                url: tracks[track as usize - 1].url.clone(),
+                title: None,
+                artists: Vec::new(),
+            };
+            let job = db
+                .insert_archive_job(&candidate, Some((playlist, position)))
+                .unwrap();
+            jobs.push((job, track, position));
         }
-        tracks
+
+        // The jobs finish in reverse order
+        for &(job, track, position) in jobs.iter().rev() {
+            db.insert_tracks(tracks[track as usize - 1..track as usize].iter())
+                .unwrap();
+            db.set_archive_job_status(job, ArchiveJobStatus::Succeeded, None, Some(track))
+                .unwrap();
+            db.insert_imported_playlist_track(playlist, position, track)
+                .unwrap();
+        }
+        let track_ids = db.playlist(playlist).unwrap().unwrap().track_ids;
+        assert_eq!(track_ids, [2, 3, 1, 4]);
+    }
+
+    #[test]
+    fn changing_a_password_revokes_the_other_credentials() {
+        let (mut db, _dir) = temp_database();
+        let new_user = NewUser {
+            name: "user".to_string(),
+            password: "a password".to_string(),
+            role: Role::Contributor,
+        };
+        let user = db.insert_user(&new_user, "old hash").unwrap();
+        let current = db.insert_session("current", user.id).unwrap();
+        db.insert_session("other", user.id).unwrap();
+        let new_token = NewAccessToken {
+            name: "script".to_string(),
+            scopes: vec![Scope::ReadLibrary],
+            date_expires: None,
+        };
+        db.insert_access_token("token", user.id, &new_token)
+            .unwrap();
+
+        db.set_user_password(user.id, "new hash", Some(current.id), None)
+            .unwrap();
+        let sessions = db.sessions(Some(user.id)).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, current.id);
+        assert!(db.access_tokens(Some(user.id)).unwrap().is_empty());
+
+        // Admins reset the passwords of others without keeping any of their sessions
+        db.set_user_password(user.id, "reset hash", None, None)
+            .unwrap();
+        assert!(db.sessions(Some(user.id)).unwrap().is_empty());
+        assert!(db.set_user_password(999, "hash", None, None).is_err());
     }
 }
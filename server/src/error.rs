@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::error::{ApiError, ErrorKind};
+use tracing::error;
+
+/// Error of a request or database call. Responses carry the status code of the kind and an
+/// ApiError as JSON body.
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{message}")]
+    TooManyRequests {
+        message: String,
+        retry_after: Duration,
+    },
+    #[error("{0}")]
+    BadGateway(String),
+    // Clients only learn that something went wrong, the details are logged
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl ServerError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ServerError::BadRequest(_) => ErrorKind::BadRequest,
+            ServerError::Unauthorized(_) => ErrorKind::Unauthorized,
+            ServerError::Forbidden(_) => ErrorKind::Forbidden,
+            ServerError::NotFound(_) => ErrorKind::NotFound,
+            ServerError::Conflict(_) => ErrorKind::Conflict,
+            ServerError::TooManyRequests { .. } => ErrorKind::TooManyRequests,
+            ServerError::BadGateway(_) => ErrorKind::BadGateway,
+            ServerError::Internal(_) => ErrorKind::Internal,
+        }
+    }
+
+    // Invalid input, e.g. a body that does not parse or fails validation
+    pub fn bad_request(e: impl ToString) -> Self {
+        ServerError::BadRequest(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for ServerError {
+    fn from(e: rusqlite::Error) -> Self {
+        ServerError::Internal(e.into())
+    }
+}
+
+impl From<std::io::Error> for ServerError {
+    fn from(e: std::io::Error) -> Self {
+        ServerError::Internal(e.into())
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        let kind = self.kind();
+        let message = match &self {
+            ServerError::Internal(e) => {
+                error!("Request failed: {e:#}");
+                "Internal server error".to_string()
+            }
+            _ => self.to_string(),
+        };
+        let body = serde_json::to_string(&ApiError::new(kind, message)).unwrap();
+        let status = StatusCode::from_u16(kind.status()).unwrap();
+        let mut response =
+            (status, [(header::CONTENT_TYPE, "application/json")], body).into_response();
+        if let ServerError::TooManyRequests { retry_after, .. } = self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs().max(1)),
+            );
+        }
+        response
+    }
+}
@@ -1,130 +1,344 @@
-use std::{
-    env::set_var,
-    fs,
-    path::PathBuf,
-    str::FromStr,
-    sync::{Arc, Mutex},
-};
+use std::{fs, net::SocketAddr, path::PathBuf, process::exit, sync::Arc};
 
 use archiver::archiver_task;
-use auth::{auth_middleware, use_secret, TokenManager, TokenQuery};
+use auth::{
+    auth_middleware, create_access_token, ensure_admin, get_access_tokens, get_sessions, login,
+    logout, revoke_access_token, revoke_session, session_sweeper_task, DownloadSigner,
+    LoginLimiter, TokenManager,
+};
 use axum::{
-    extract::Query,
+    extract::{Path, Query},
+    http::{self, header, HeaderName, HeaderValue},
     middleware,
-    routing::{get, post},
-    Router,
+    routing::get,
+    Extension, Form, Router,
 };
-use database::Database;
+use client::serve_client;
+use common::api;
+use config::{config, Config};
+use crossbeam::channel::Sender;
+use error::ServerError;
 use once_cell::sync::Lazy;
-use requests::{archive_track, download_tracks, get_all_tracks};
-use tower_http::cors::{Any, CorsLayer};
-use tracing::{debug, warn, Level};
+use pool::DatabasePool;
+use requests::{
+    add_playlist_tracks, archive_collection, archive_track, create_download_url, create_playlist,
+    create_user, delete_playlist, delete_tracks, delete_user, download_signed_tracks,
+    download_tracks, get_all_tracks, get_archive_job, get_archive_jobs, get_current_user,
+    get_playlists, get_trash, get_users, order_playlist_tracks, order_playlists, purge_tracks,
+    query_tracks, remove_playlist_tracks, rename_playlist, restore_tracks, set_user_password,
+    set_user_role, stream_track, update_track,
+};
+use router::{ApiRouter, Route};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, info, Level};
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+use trash::trash_purger_task;
 
 pub mod archiver;
 pub mod auth;
+pub mod client;
+pub mod config;
 pub mod database;
+pub mod error;
+pub mod migrations;
+pub mod openapi;
+pub mod pool;
 pub mod requests;
+pub mod router;
+pub mod trash;
 
 pub static ARCHIVE_DIR: Lazy<PathBuf> = Lazy::new(get_archive_dir);
 pub static DOWNLOAD_DIR: Lazy<PathBuf> = Lazy::new(get_download_dir);
 pub static TRACK_DIR: Lazy<PathBuf> = Lazy::new(get_track_dir);
+pub static TRASH_DIR: Lazy<PathBuf> = Lazy::new(get_trash_dir);
+pub static TRASH_RETENTION: Lazy<chrono::Duration> = Lazy::new(get_trash_retention);
+pub static SESSION_SLIDING_TTL: Lazy<chrono::Duration> = Lazy::new(get_session_sliding_ttl);
+pub static SESSION_ABSOLUTE_TTL: Lazy<chrono::Duration> = Lazy::new(get_session_absolute_ttl);
 
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
-    setup_tracing();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e:#}");
+            exit(1);
+        }
+    };
+    setup_tracing(&config.log_level);
 
-    let database = Arc::new(Mutex::new(Database::new(ARCHIVE_DIR.clone())));
-    let token_manager = Arc::new(TokenManager::new(&std::env::var("SECRET").unwrap()));
+    let database = match DatabasePool::open(ARCHIVE_DIR.clone()) {
+        Ok(database) => database,
+        Err(e) => {
+            error!("Unable to open the database: {e:#}");
+            exit(1);
+        }
+    };
+    if let Err(e) = ensure_admin(&database).await {
+        error!("Unable to create the first admin: {e:#}");
+        exit(1);
+    }
+    let token_manager = Arc::new(TokenManager::new(database.clone()));
+    let login_limiter = Arc::new(LoginLimiter::new(config.trusted_proxies.clone()));
     let (sender, receiver) = crossbeam::channel::unbounded();
 
     let _database = database.clone();
     tokio::task::spawn_blocking(move || archiver_task(receiver, _database));
+    let _database = database.clone();
+    tokio::task::spawn_blocking(move || trash_purger_task(_database));
+    let _token_manager = token_manager.clone();
+    tokio::spawn(session_sweeper_task(_token_manager));
 
-    let cors = CorsLayer::new()
-        .allow_methods(Any)
-        .allow_origin(Any)
-        .allow_headers(Any);
+    let cors = cors_layer(&config.cors_origins);
 
-    let _token_manager = token_manager.clone();
+    let api = app(database, token_manager, login_limiter, sender)
+        .into_router()
+        .route("/openapi.json", get(openapi::serve_document))
+        .route("/docs", get(openapi::serve_docs_page))
+        // Unknown api routes must not fall back to the client
+        .fallback(|| async { ServerError::NotFound("No endpoint has this route".into()) });
     let app = Router::new()
-        .route(
-            "/get_all_tracks",
-            get({
-                let db = database.clone();
-                move || get_all_tracks(db)
-            }),
-        )
-        .route(
-            "/download_tracks",
-            post({
-                let db = database.clone();
-                move |_query: Query<TokenQuery>, body| download_tracks(db, body)
-            }),
-        )
-        .route(
-            "/archive_track",
-            post({
-                let sender = sender.clone();
-                move |body| archive_track(sender, body)
-            }),
-        )
-        .layer(middleware::from_fn(move |jar, query, request, next| {
-            auth_middleware(jar, query, _token_manager.clone(), request, next)
-        }))
-        .route(
-            "/use_secret",
-            post({
-                let token_manager = token_manager.clone();
-                move |body| use_secret(token_manager, body)
-            }),
-        )
+        .nest("/api", api)
+        .fallback_service(get(serve_client))
         .layer(cors);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:7000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listener = match tokio::net::TcpListener::bind(config.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Unable to listen on {}: {e}", config.bind);
+            exit(1);
+        }
+    };
+    info!("Listening on {}", config.bind);
+    // Connection infos provide the client addresses logins are limited by
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
-fn setup_tracing() {
-    set_var("RUST_LOG", "none,server=trace,common=trace");
+/// Serves every endpoint, main nests them under /api. The OpenAPI document lists the same ones.
+pub fn app(
+    database: DatabasePool,
+    token_manager: Arc<TokenManager>,
+    login_limiter: Arc<LoginLimiter>,
+    sender: Sender<()>,
+) -> ApiRouter {
+    let download_signer = Arc::new(DownloadSigner::new());
+    let router = ApiRouter::new()
+        .endpoint::<api::GetAllTracks, _>({
+            let db = database.clone();
+            move || get_all_tracks(db)
+        })
+        .endpoint::<api::QueryTracks, _>({
+            let db = database.clone();
+            move |Query(query)| query_tracks(db, query)
+        })
+        .endpoint::<api::DownloadTracks, _>({
+            let db = database.clone();
+            move |Form(form)| download_tracks(db, form)
+        })
+        .endpoint::<api::CreateDownloadUrl, _>({
+            let signer = download_signer.clone();
+            move |body| create_download_url(signer, body)
+        })
+        .endpoint::<api::StreamTrack, _>({
+            let db = database.clone();
+            move |Path(id), request| stream_track(db, id, request)
+        })
+        .endpoint::<api::UpdateTrack, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id), body| update_track(db, user, id, body)
+        })
+        .endpoint::<api::DeleteTracks, _>({
+            let db = database.clone();
+            move |Extension(user), body| delete_tracks(db, user, body)
+        })
+        .endpoint::<api::GetTrash, _>({
+            let db = database.clone();
+            move || get_trash(db)
+        })
+        .endpoint::<api::RestoreTracks, _>({
+            let db = database.clone();
+            move |Extension(user), body| restore_tracks(db, user, body)
+        })
+        .endpoint::<api::PurgeTracks, _>({
+            let db = database.clone();
+            move |Extension(user), body| purge_tracks(db, user, body)
+        })
+        .endpoint::<api::ArchiveTrack, _>({
+            let db = database.clone();
+            let sender = sender.clone();
+            move |Extension(user), body| archive_track(db, sender, user, body)
+        })
+        .endpoint::<api::ArchiveCollection, _>({
+            let db = database.clone();
+            let sender = sender.clone();
+            move |Extension(user), body| archive_collection(db, sender, user, body)
+        })
+        .endpoint::<api::GetArchiveJobs, _>({
+            let db = database.clone();
+            move || get_archive_jobs(db)
+        })
+        .endpoint::<api::GetArchiveJob, _>({
+            let db = database.clone();
+            move |Path(id)| get_archive_job(db, id)
+        })
+        .endpoint::<api::GetPlaylists, _>({
+            let db = database.clone();
+            move || get_playlists(db)
+        })
+        .endpoint::<api::CreatePlaylist, _>({
+            let db = database.clone();
+            move |Extension(user), body| create_playlist(db, user, body)
+        })
+        .endpoint::<api::RenamePlaylist, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id), body| rename_playlist(db, user, id, body)
+        })
+        .endpoint::<api::DeletePlaylist, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id)| delete_playlist(db, user, id)
+        })
+        .endpoint::<api::OrderPlaylists, _>({
+            let db = database.clone();
+            move |Extension(user), body| order_playlists(db, user, body)
+        })
+        .endpoint::<api::AddPlaylistTracks, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id), body| add_playlist_tracks(db, user, id, body)
+        })
+        .endpoint::<api::RemovePlaylistTracks, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id), body| remove_playlist_tracks(db, user, id, body)
+        })
+        .endpoint::<api::OrderPlaylistTracks, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id), body| order_playlist_tracks(db, user, id, body)
+        })
+        .endpoint::<api::GetCurrentUser, _>(move |Extension(user)| get_current_user(user))
+        .endpoint::<api::GetUsers, _>({
+            let db = database.clone();
+            move |Extension(user)| get_users(db, user)
+        })
+        .endpoint::<api::CreateUser, _>({
+            let db = database.clone();
+            move |Extension(user), body| create_user(db, user, body)
+        })
+        .endpoint::<api::SetUserRole, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id), body| set_user_role(db, user, id, body)
+        })
+        .endpoint::<api::SetUserPassword, _>({
+            let db = database.clone();
+            move |Extension(user), Extension(credential), Path(id), body| {
+                set_user_password(db, user, credential, id, body)
+            }
+        })
+        .endpoint::<api::DeleteUser, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id)| delete_user(db, user, id)
+        })
+        .endpoint::<api::Logout, _>({
+            let token_manager = token_manager.clone();
+            move |Extension(credential)| logout(token_manager, credential)
+        })
+        .endpoint::<api::GetSessions, _>({
+            let db = database.clone();
+            move |Extension(user)| get_sessions(db, user)
+        })
+        .endpoint::<api::RevokeSession, _>({
+            let db = database.clone();
+            let token_manager = token_manager.clone();
+            move |Extension(user), Path(id)| revoke_session(db, token_manager, user, id)
+        })
+        .endpoint::<api::GetAccessTokens, _>({
+            let db = database.clone();
+            move |Extension(user)| get_access_tokens(db, user)
+        })
+        .endpoint::<api::CreateAccessToken, _>({
+            let token_manager = token_manager.clone();
+            move |Extension(user), body| create_access_token(token_manager, user, body)
+        })
+        .endpoint::<api::RevokeAccessToken, _>({
+            let db = database.clone();
+            move |Extension(user), Path(id)| revoke_access_token(db, user, id)
+        });
+
+    // Login and signed downloads are the only endpoints served without a credential
+    let routes: Arc<[Route]> = router.routes().into();
+    router
+        .map_router(|router| {
+            router.layer(middleware::from_fn({
+                let db = database.clone();
+                let token_manager = token_manager.clone();
+                move |jar, request, next| {
+                    auth_middleware(
+                        jar,
+                        token_manager.clone(),
+                        db.clone(),
+                        routes.clone(),
+                        request,
+                        next,
+                    )
+                }
+            }))
+        })
+        .endpoint::<api::Login, _>({
+            let db = database.clone();
+            let token_manager = token_manager.clone();
+            let limiter = login_limiter.clone();
+            move |address, headers, body| login(db, token_manager, limiter, address, headers, body)
+        })
+        .endpoint::<api::DownloadSignedTracks, _>({
+            let db = database.clone();
+            move |Query(download)| download_signed_tracks(db, download_signer, download)
+        })
+}
+
+fn setup_tracing(filter: &str) {
     let subscriber = tracing_subscriber::fmt()
         .compact()
         .with_max_level(Level::TRACE)
         .with_span_events(FmtSpan::ACTIVE)
         .with_line_number(true)
-        .with_env_filter(EnvFilter::from_default_env())
+        .with_env_filter(EnvFilter::new(filter))
         .finish();
     tracing::subscriber::set_global_default(subscriber).unwrap();
 }
 
-fn get_archive_dir() -> PathBuf {
-    let raw = match std::env::var("HARMONY_ARCHIVE_DIR") {
-        Ok(raw) => raw,
-        Err(e) => {
-            warn!("Unable to get HARMONY_ARCHIVE_DIR due to: '{e}'. Falling back to './harchive'");
-            "./harchive".to_owned()
-        }
-    };
-    debug!("Creating/validating archive directory: {}", raw);
-
-    let path = PathBuf::from_str(&raw).expect("Expected HARMONY_ARCHIVE_DIR to be a valid path");
-
-    // Create directory
-    if path.exists() {
-        if !path.is_dir() {
-            panic!("{:?} should be a directory", &path);
-        }
-    } else {
-        if let Err(e) = fs::create_dir_all(&path) {
-            panic!(
-                "Expected to be able to create all parent directories of {:?}, error: {e}",
-                &path
-            );
-        }
+// Origins were validated when the config was loaded. Listed origins may send credentials, any
+// origin may not, as browsers would then send the cookies of every user to every site.
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    let cors = CorsLayer::new()
+        .allow_methods([
+            http::Method::GET,
+            http::Method::POST,
+            http::Method::PUT,
+            http::Method::PATCH,
+            http::Method::DELETE,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            HeaderName::from_static(api::CSRF_HEADER),
+        ]);
+    if origins.iter().any(|origin| origin == "*") {
+        return cors.allow_origin(AllowOrigin::any());
     }
+    cors.allow_origin(AllowOrigin::list(
+        origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin).unwrap()),
+    ))
+    .allow_credentials(true)
+}
 
-    path
+// The directory was created when the config was loaded
+fn get_archive_dir() -> PathBuf {
+    config().archive_dir.clone()
 }
 
 fn get_download_dir() -> PathBuf {
@@ -140,3 +354,22 @@ fn get_track_dir() -> PathBuf {
     fs::create_dir_all(&archive_dir).unwrap();
     archive_dir
 }
+
+fn get_trash_dir() -> PathBuf {
+    let mut archive_dir = ARCHIVE_DIR.clone();
+    archive_dir.push("trash");
+    fs::create_dir_all(&archive_dir).unwrap();
+    archive_dir
+}
+
+fn get_trash_retention() -> chrono::Duration {
+    chrono::Duration::days(config().trash_retention_days as i64)
+}
+
+fn get_session_sliding_ttl() -> chrono::Duration {
+    chrono::Duration::minutes(config().session.sliding_ttl_minutes as i64)
+}
+
+fn get_session_absolute_ttl() -> chrono::Duration {
+    chrono::Duration::hours(config().session.absolute_ttl_hours as i64)
+}
@@ -0,0 +1,365 @@
+use anyhow::{bail, Context};
+use rusqlite::{Connection, OptionalExtension, Transaction};
+use tracing::info;
+
+struct Migration {
+    description: &'static str,
+    apply: fn(&Transaction) -> anyhow::Result<()>,
+}
+
+// Migrations in the order they are applied. The user_version of the database is the number of
+// applied migrations, so released migrations must never be changed or reordered, only appended.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Create tables",
+        apply: create_tables,
+    },
+    Migration {
+        description: "Index track urls and artist names",
+        apply: add_indexes,
+    },
+    Migration {
+        description: "Add foreign keys to track_artists",
+        apply: add_track_artist_foreign_keys,
+    },
+    Migration {
+        description: "Store the playlist positions of archive jobs",
+        apply: add_archive_job_playlist_positions,
+    },
+];
+
+/// Schema version of databases that are fully migrated by this server
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+// Applies all migrations the database is missing, each in its own transaction. Fails without
+// touching the database if it was created by a newer server.
+pub fn migrate(con: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = con.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "The database has schema version {version}, but this server only supports up to \
+            version {SCHEMA_VERSION}. Upgrade the server or restore a backup of the database."
+        );
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let version = index + 1;
+        let tx = con.transaction()?;
+        (migration.apply)(&tx)
+            .with_context(|| format!("Migration {version} ({}) failed", migration.description))?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        info!(
+            "Migrated database to version {version}: {}",
+            migration.description
+        );
+    }
+    Ok(())
+}
+
+// Databases of servers without migrations have user_version 0 and may lack some of the columns,
+// so every statement must also work on them
+fn create_tables(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS tracks(
+            id INTEGER NOT NULL PRIMARY KEY,
+            url TEXT NOT NULL,
+            title TEXT NOT NULL,
+            date_archived TEXT NOT NULL,
+            source TEXT NOT NULL DEFAULT 'youtube');
+
+        CREATE TABLE IF NOT EXISTS track_artists(
+            track_id INTEGER NOT NULL,
+            artist_id INTEGER NOT NULL,
+            PRIMARY KEY (track_id, artist_id));
+
+        CREATE TABLE IF NOT EXISTS artists(
+            id INTEGER NOT NULL PRIMARY KEY,
+            name TEXT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS archive_jobs(
+            id INTEGER NOT NULL PRIMARY KEY,
+            url TEXT NOT NULL,
+            title TEXT,
+            artists TEXT NOT NULL,
+            status TEXT NOT NULL,
+            error TEXT,
+            track_id INTEGER,
+            date_created TEXT NOT NULL,
+            date_updated TEXT NOT NULL,
+            playlist_id INTEGER);
+
+        CREATE TABLE IF NOT EXISTS playlists(
+            id INTEGER NOT NULL PRIMARY KEY,
+            name TEXT NOT NULL,
+            position INTEGER NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS playlist_tracks(
+            playlist_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            track_id INTEGER NOT NULL,
+            PRIMARY KEY (playlist_id, position));
+
+        CREATE TABLE IF NOT EXISTS trash(
+            id INTEGER NOT NULL PRIMARY KEY,
+            url TEXT NOT NULL,
+            title TEXT NOT NULL,
+            artists TEXT NOT NULL,
+            date_archived TEXT NOT NULL,
+            date_trashed TEXT NOT NULL,
+            source TEXT NOT NULL DEFAULT 'youtube');
+
+        CREATE TABLE IF NOT EXISTS users(
+            id INTEGER NOT NULL PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            password_hash TEXT NOT NULL,
+            role TEXT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS sessions(
+            id INTEGER NOT NULL PRIMARY KEY,
+            token_hash TEXT NOT NULL UNIQUE,
+            user_id INTEGER NOT NULL,
+            date_created TEXT NOT NULL,
+            date_last_seen TEXT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS access_tokens(
+            id INTEGER NOT NULL PRIMARY KEY,
+            token_hash TEXT NOT NULL UNIQUE,
+            user_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            scopes TEXT NOT NULL,
+            date_created TEXT NOT NULL,
+            date_expires TEXT,
+            date_last_used TEXT);",
+    )?;
+
+    // Tracks archived before sources were stored are all from YouTube
+    add_column_if_missing(tx, "tracks", "source", "TEXT NOT NULL DEFAULT 'youtube'")?;
+    add_column_if_missing(tx, "trash", "source", "TEXT NOT NULL DEFAULT 'youtube'")?;
+    add_column_if_missing(tx, "archive_jobs", "playlist_id", "INTEGER")?;
+    Ok(())
+}
+
+fn add_indexes(tx: &Transaction) -> anyhow::Result<()> {
+    // Duplicates can only be resolved by the user, as each has its own file and playlist entries
+    let duplicate: Option<(String, String)> = tx
+        .query_row(
+            "SELECT url, group_concat(id, ', ') FROM tracks
+            GROUP BY url HAVING count(*) > 1 LIMIT 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    if let Some((url, ids)) = duplicate {
+        bail!("Tracks {ids} share the url {url}, trash all but one of them and start again");
+    }
+
+    tx.execute_batch(
+        "CREATE UNIQUE INDEX tracks_url ON tracks(url);
+        -- Artists are looked up case insensitively
+        CREATE INDEX artists_name ON artists(name COLLATE NOCASE);",
+    )?;
+    Ok(())
+}
+
+// SQLite can not add foreign keys to an existing table, so the table is rebuilt
+fn add_track_artist_foreign_keys(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE track_artists_new(
+            track_id INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+            artist_id INTEGER NOT NULL REFERENCES artists(id),
+            PRIMARY KEY (track_id, artist_id));
+
+        -- The rowid is kept, as it is the order of the artists of a track. Entries of removed
+        -- tracks or artists are dropped.
+        INSERT INTO track_artists_new (rowid, track_id, artist_id)
+            SELECT rowid, track_id, artist_id FROM track_artists
+            WHERE track_id IN (SELECT id FROM tracks) AND artist_id IN (SELECT id FROM artists)
+            ORDER BY rowid;
+
+        DROP TABLE track_artists;
+        ALTER TABLE track_artists_new RENAME TO track_artists;
+        CREATE INDEX track_artists_artist_id ON track_artists(artist_id);",
+    )?;
+    Ok(())
+}
+
+// Jobs queued before have no position, their tracks are appended to the playlist
+fn add_archive_job_playlist_positions(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute_batch("ALTER TABLE archive_jobs ADD COLUMN playlist_position INTEGER;")?;
+    Ok(())
+}
+
+// Adds a column to a table created before the column existed
+fn add_column_if_missing(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> anyhow::Result<()> {
+    let exists: bool = tx.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+        (table, column),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        tx.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(con: &Connection) -> usize {
+        con.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    // Database of a server without migrations, from before sources and playlists were stored
+    fn baseline_database() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE tracks(
+                id INTEGER NOT NULL PRIMARY KEY,
+                url TEXT NOT NULL,
+                title TEXT NOT NULL,
+                date_archived TEXT NOT NULL);
+            CREATE TABLE track_artists(
+                track_id INTEGER NOT NULL,
+                artist_id INTEGER NOT NULL,
+                PRIMARY KEY (track_id, artist_id));
+            CREATE TABLE artists(id INTEGER NOT NULL PRIMARY KEY, name TEXT NOT NULL);
+            CREATE TABLE archive_jobs(
+                id INTEGER NOT NULL PRIMARY KEY,
+                url TEXT NOT NULL,
+                title TEXT,
+                artists TEXT NOT NULL,
+                status TEXT NOT NULL,
+                error TEXT,
+                track_id INTEGER,
+                date_created TEXT NOT NULL,
+                date_updated TEXT NOT NULL);
+            CREATE TABLE trash(
+                id INTEGER NOT NULL PRIMARY KEY,
+                url TEXT NOT NULL,
+                title TEXT NOT NULL,
+                artists TEXT NOT NULL,
+                date_archived TEXT NOT NULL,
+                date_trashed TEXT NOT NULL);
+
+            INSERT INTO tracks VALUES (1, 'u1', 'One', '2024-01-01'), (2, 'u2', 'Two', '2024-01-01');
+            INSERT INTO artists VALUES (1, 'Zed'), (2, 'Amy'), (3, 'Bob');",
+        )
+        .unwrap();
+        con
+    }
+
+    #[test]
+    fn baseline_databases_are_migrated() {
+        let mut con = baseline_database();
+        con.execute(
+            "INSERT INTO archive_jobs VALUES (1, 'u3', NULL, '', 'pending', NULL, NULL, '', '')",
+            [],
+        )
+        .unwrap();
+        migrate(&mut con).unwrap();
+        assert_eq!(user_version(&con), SCHEMA_VERSION);
+
+        let source: String = con
+            .query_row("SELECT source FROM tracks WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(source, "youtube");
+        let (playlist_id, position): (Option<u32>, Option<u32>) = con
+            .query_row(
+                "SELECT playlist_id, playlist_position FROM archive_jobs",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((playlist_id, position), (None, None));
+        con.execute(
+            "INSERT INTO trash (url, title, artists, date_archived, date_trashed)
+            VALUES ('u4', 'Four', '', '', '')",
+            [],
+        )
+        .unwrap();
+
+        // Migrating again changes nothing
+        migrate(&mut con).unwrap();
+        assert_eq!(user_version(&con), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn duplicate_urls_stop_the_migration() {
+        let mut con = baseline_database();
+        con.execute("UPDATE tracks SET url = 'u1'", []).unwrap();
+        let error = format!("{:#}", migrate(&mut con).unwrap_err());
+        assert!(
+            error.contains("Tracks 1, 2 share the url u1, trash all but one of them"),
+            "{error}"
+        );
+        // The migrations before the failed one stay applied
+        assert_eq!(user_version(&con), 1);
+
+        con.execute("UPDATE tracks SET url = 'u2' WHERE id = 2", [])
+            .unwrap();
+        migrate(&mut con).unwrap();
+        assert_eq!(user_version(&con), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn track_artists_keep_their_order() {
+        let mut con = baseline_database();
+        // Artist 9 and track 9 do not exist
+        con.execute_batch(
+            "INSERT INTO track_artists VALUES (1, 2), (2, 3), (1, 1), (1, 9), (9, 1), (1, 3);",
+        )
+        .unwrap();
+        migrate(&mut con).unwrap();
+
+        let artists: Vec<(u32, u32)> = con
+            .prepare("SELECT track_id, artist_id FROM track_artists ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(artists, [(1, 2), (2, 3), (1, 1), (1, 3)]);
+
+        con.execute_batch("PRAGMA foreign_keys = ON; DELETE FROM tracks WHERE id = 1;")
+            .unwrap();
+        let remaining: u32 = con
+            .query_row("SELECT count(*) FROM track_artists", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(remaining, 1);
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let mut con = baseline_database();
+        con.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        let error = migrate(&mut con).unwrap_err().to_string();
+        assert!(
+            error.contains("this server only supports up to version"),
+            "{error}"
+        );
+        assert_eq!(user_version(&con), SCHEMA_VERSION + 1);
+        // Nothing was migrated
+        let has_source: bool = con
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM pragma_table_info('tracks') WHERE name = 'source')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!has_source);
+    }
+}
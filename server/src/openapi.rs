@@ -0,0 +1,335 @@
+//! OpenAPI 3 document of the endpoints in common::api, generated from their types. It is served
+//! at /openapi.json, together with an interactive page at /docs.
+
+use std::any::TypeId;
+
+use axum::{response::Html, Json};
+use common::{
+    api::{self, Endpoint, File, Method},
+    error::ApiError,
+};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    JsonSchema,
+};
+use serde_json::{json, Map, Value};
+
+const DOCS_PAGE: &str = include_str!("../assets/docs.html");
+
+const DESCRIPTION: &str = "Every endpoint except login and signed downloads needs a credential. \
+    Personal access tokens are sent as `Authorization: Bearer <secret>` and only reach the \
+    endpoints their scopes allow. Without one, the session of a login is read from the HttpOnly \
+    `api_token` cookie login sets, and requests other than gets also need the `x-csrf-token` \
+    header, holding the value of the `csrf_token` cookie login sets. \
+    Browsers download tracks from urls signed by CreateDownloadUrl, which expire after a few \
+    minutes and when the server restarts. Errors respond with an ApiError.";
+
+// Served outside of auth_middleware
+pub async fn serve_document() -> Json<Value> {
+    Json(document())
+}
+
+pub async fn serve_docs_page() -> Html<&'static str> {
+    Html(DOCS_PAGE)
+}
+
+/// Lists every endpoint the server serves, the test below fails once one is missing
+pub fn document() -> Value {
+    let mut doc = Document::new();
+
+    doc.endpoint::<api::Login>("Sessions", "Log in with name and password");
+    doc.endpoint::<api::Logout>("Sessions", "Log out");
+    doc.endpoint::<api::GetSessions>("Sessions", "List the sessions of the user");
+    doc.endpoint::<api::RevokeSession>("Sessions", "Revoke a session");
+    doc.endpoint::<api::GetAccessTokens>("Sessions", "List the access tokens of the user");
+    doc.endpoint::<api::CreateAccessToken>("Sessions", "Create an access token");
+    doc.endpoint::<api::RevokeAccessToken>("Sessions", "Revoke an access token");
+
+    doc.endpoint::<api::GetAllTracks>("Tracks", "List all tracks");
+    doc.endpoint::<api::QueryTracks>("Tracks", "Filter, sort and page the tracks");
+    doc.endpoint::<api::DownloadTracks>("Tracks", "Download tracks");
+    doc.endpoint::<api::CreateDownloadUrl>("Tracks", "Sign a download url for tracks");
+    doc.endpoint::<api::DownloadSignedTracks>("Tracks", "Download tracks from a signed url");
+    doc.endpoint::<api::StreamTrack>("Tracks", "Stream a track");
+    doc.endpoint::<api::UpdateTrack>("Tracks", "Update a track");
+    doc.endpoint::<api::DeleteTracks>("Tracks", "Move tracks into the trash");
+    doc.endpoint::<api::GetTrash>("Tracks", "List the trashed tracks");
+    doc.endpoint::<api::RestoreTracks>("Tracks", "Restore trashed tracks");
+    doc.endpoint::<api::PurgeTracks>("Tracks", "Delete trashed tracks for good");
+
+    doc.endpoint::<api::ArchiveTrack>("Archive", "Archive a track");
+    doc.endpoint::<api::ArchiveCollection>("Archive", "Archive a playlist or channel");
+    doc.endpoint::<api::GetArchiveJobs>("Archive", "List the archive jobs");
+    doc.endpoint::<api::GetArchiveJob>("Archive", "Get an archive job");
+
+    doc.endpoint::<api::GetPlaylists>("Playlists", "List the playlists");
+    doc.endpoint::<api::CreatePlaylist>("Playlists", "Create a playlist");
+    doc.endpoint::<api::RenamePlaylist>("Playlists", "Rename a playlist");
+    doc.endpoint::<api::DeletePlaylist>("Playlists", "Delete a playlist");
+    doc.endpoint::<api::OrderPlaylists>("Playlists", "Order the playlists");
+    doc.endpoint::<api::AddPlaylistTracks>("Playlists", "Add tracks to a playlist");
+    doc.endpoint::<api::RemovePlaylistTracks>("Playlists", "Remove tracks from a playlist");
+    doc.endpoint::<api::OrderPlaylistTracks>("Playlists", "Order the tracks of a playlist");
+
+    doc.endpoint::<api::GetCurrentUser>("Users", "Get the user of the credential");
+    doc.endpoint::<api::GetUsers>("Users", "List the users");
+    doc.endpoint::<api::CreateUser>("Users", "Create a user");
+    doc.endpoint::<api::SetUserRole>("Users", "Change the role of a user");
+    doc.endpoint::<api::SetUserPassword>("Users", "Change the password of a user");
+    doc.endpoint::<api::DeleteUser>("Users", "Delete a user");
+
+    doc.finish()
+}
+
+// OpenAPI writes the parameters of a path in braces
+fn openapi_path(route: &str) -> String {
+    route.replace(":id", "{id}")
+}
+
+fn method_name(method: Method) -> String {
+    method.as_str().to_lowercase()
+}
+
+// Endpoints without a request or response use (), which has no body
+fn is_unit<T: 'static>() -> bool {
+    TypeId::of::<T>() == TypeId::of::<()>()
+}
+
+struct Document {
+    // Collects the schemas of all shared types, operations reference them
+    generator: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+impl Document {
+    fn new() -> Self {
+        Self {
+            generator: SchemaGenerator::new(SchemaSettings::openapi3()),
+            paths: Map::new(),
+        }
+    }
+
+    fn endpoint<E>(&mut self, tag: &str, summary: &str)
+    where
+        E: Endpoint + 'static,
+        E::Request: JsonSchema + 'static,
+        E::Response: JsonSchema + 'static,
+    {
+        let name = std::any::type_name::<E>().rsplit("::").next().unwrap();
+        let mut operation = json!({
+            "tags": [tag],
+            "summary": summary,
+            "operationId": name,
+            "responses": {
+                "200": self.response::<E::Response>(),
+                "default": {
+                    "description": "The error of the request",
+                    "content": {
+                        "application/json": {
+                            "schema": self.generator.subschema_for::<ApiError>(),
+                        },
+                    },
+                },
+            },
+        });
+
+        let mut parameters = Vec::new();
+        if E::ROUTE.contains(":id") {
+            parameters.push(json!({
+                "name": "id",
+                "in": "path",
+                "required": true,
+                "schema": self.generator.subschema_for::<u32>(),
+            }));
+        }
+        if !is_unit::<E::Request>() {
+            if E::METHOD.has_body() {
+                operation["requestBody"] = self.request_body::<E>();
+            } else {
+                parameters.extend(self.query_parameters::<E::Request>());
+            }
+        }
+        if !parameters.is_empty() {
+            operation["parameters"] = parameters.into();
+        }
+
+        match E::SCOPE {
+            Some(scope) => {
+                operation["description"] =
+                    format!("Access tokens need the `{}` scope.", scope.as_str()).into();
+            }
+            None => operation["security"] = json!([]),
+        }
+
+        if let Some((method, route)) = E::DEPRECATED_ROUTE {
+            let mut deprecated = operation.clone();
+            deprecated["deprecated"] = true.into();
+            deprecated["operationId"] = format!("{name}Deprecated").into();
+            deprecated["description"] = format!(
+                "Deprecated, use {} {} instead. {}",
+                E::METHOD.as_str(),
+                E::ROUTE,
+                operation["description"].as_str().unwrap_or_default()
+            )
+            .trim_end()
+            .into();
+            self.insert(method, route, deprecated);
+        }
+        self.insert(E::METHOD, E::ROUTE, operation);
+    }
+
+    fn insert(&mut self, method: Method, route: &str, operation: Value) {
+        self.paths
+            .entry(openapi_path(route))
+            .or_insert_with(|| json!({}))[method_name(method)] = operation;
+    }
+
+    fn response<T: JsonSchema + 'static>(&mut self) -> Value {
+        if is_unit::<T>() {
+            return json!({ "description": "Success, without a body" });
+        }
+        let content_type = if TypeId::of::<T>() == TypeId::of::<File>() {
+            "application/octet-stream"
+        } else {
+            "application/json"
+        };
+        json!({
+            "description": "Success",
+            "content": {
+                content_type: { "schema": self.generator.subschema_for::<T>() },
+            },
+        })
+    }
+
+    // Requests to file endpoints are posted as forms, so browsers can save the response
+    fn request_body<E>(&mut self) -> Value
+    where
+        E: Endpoint,
+        E::Request: JsonSchema,
+        E::Response: 'static,
+    {
+        let content_type = if TypeId::of::<E::Response>() == TypeId::of::<File>() {
+            "application/x-www-form-urlencoded"
+        } else {
+            "application/json"
+        };
+        json!({
+            "required": true,
+            "content": {
+                content_type: { "schema": self.generator.subschema_for::<E::Request>() },
+            },
+        })
+    }
+
+    // Gets send their request as query, one parameter per field
+    fn query_parameters<T: JsonSchema>(&mut self) -> Vec<Value> {
+        let schema = T::json_schema(&mut self.generator).into_object();
+        let Some(object) = schema.object else {
+            return Vec::new();
+        };
+        object
+            .properties
+            .into_iter()
+            .map(|(name, schema)| {
+                json!({
+                    "name": name,
+                    "in": "query",
+                    "required": object.required.contains(&name),
+                    "schema": schema,
+                })
+            })
+            .collect()
+    }
+
+    fn finish(mut self) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "Harmony",
+                "description": DESCRIPTION,
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            // The server nests the endpoints under /api, next to the client
+            "servers": [{ "url": "/api" }],
+            "paths": self.paths,
+            "components": {
+                "schemas": self.generator.take_definitions(),
+                "securitySchemes": {
+                    "accessToken": {
+                        "type": "http",
+                        "scheme": "bearer",
+                        "description": "Secret of a personal access token",
+                    },
+                    "apiTokenCookie": {
+                        "type": "apiKey",
+                        "in": "cookie",
+                        "name": "api_token",
+                        "description": "Set by login, changes also need the x-csrf-token header",
+                    },
+                },
+            },
+            // Any one of them is enough
+            "security": [
+                { "accessToken": [] },
+                { "apiTokenCookie": [] },
+            ],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        auth::{LoginLimiter, TokenManager},
+        pool::DatabasePool,
+    };
+
+    #[tokio::test]
+    async fn every_route_is_documented() {
+        let dir = TempDir::new().unwrap();
+        let database = DatabasePool::open(dir.path().to_path_buf()).unwrap();
+        let token_manager = Arc::new(TokenManager::new(database.clone()));
+        let (sender, _receiver) = crossbeam::channel::unbounded();
+        let app = crate::app(
+            database,
+            token_manager,
+            Arc::new(LoginLimiter::new(Vec::new())),
+            sender,
+        );
+
+        let document = document();
+        let paths = document["paths"].as_object().unwrap();
+        for route in app.routes() {
+            let method = method_name(route.method);
+            let operation = paths
+                .get(&openapi_path(route.path))
+                .and_then(|path| path.get(&method))
+                .unwrap_or_else(|| {
+                    panic!(
+                        "{method} {} is missing from the OpenAPI document",
+                        route.path
+                    )
+                });
+            assert_eq!(
+                operation["deprecated"].as_bool().unwrap_or_default(),
+                route.deprecated,
+                "{method} {} is documented with the wrong deprecation",
+                route.path
+            );
+        }
+        let operations = paths
+            .values()
+            .map(|path| path.as_object().unwrap().len())
+            .sum::<usize>();
+        assert_eq!(
+            operations,
+            app.routes().len(),
+            "The OpenAPI document lists endpoints the server does not serve"
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    any::Any,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::anyhow;
+use tokio::sync::Semaphore;
+
+use crate::{database::Database, error::ServerError};
+
+// Read only connections open at the same time at most
+const MAX_READERS: usize = 8;
+
+/// Runs database work on the blocking threads of tokio, so slow queries never stall the async
+/// runtime. Writes are serialized on a single connection. Reads are spread over a pool of read
+/// only connections, which do not wait for the writer as the database is in WAL mode.
+#[derive(Clone)]
+pub struct DatabasePool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    archive_dir: PathBuf,
+    writer: Mutex<Database>,
+    // Idle readers, more are opened on demand
+    readers: Mutex<Vec<Database>>,
+    reader_permits: Arc<Semaphore>,
+}
+
+impl DatabasePool {
+    // Opens the writer, which migrates the database, readers are opened once needed
+    pub fn open(archive_dir: PathBuf) -> anyhow::Result<Self> {
+        let writer = Database::new(archive_dir.clone())?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                archive_dir,
+                writer: Mutex::new(writer),
+                readers: Mutex::new(Vec::new()),
+                reader_permits: Arc::new(Semaphore::new(MAX_READERS)),
+            }),
+        })
+    }
+
+    // Runs f on a read only connection, writes fail
+    pub async fn read<T, F>(&self, f: F) -> Result<T, ServerError>
+    where
+        F: FnOnce(&mut Database) -> Result<T, ServerError> + Send + 'static,
+        T: Send + 'static,
+    {
+        // Waiting for a free reader must not block the runtime either
+        let permit = self
+            .inner
+            .reader_permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Expected the reader semaphore to never be closed");
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = inner.run_read(f);
+            drop(permit);
+            result
+        })
+        .await
+        .map_err(|e| ServerError::Internal(e.into()))?
+    }
+
+    // Runs f on the writer, after all writes started before
+    pub async fn write<T, F>(&self, f: F) -> Result<T, ServerError>
+    where
+        F: FnOnce(&mut Database) -> Result<T, ServerError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || inner.run_write(f))
+            .await
+            .map_err(|e| ServerError::Internal(e.into()))?
+    }
+
+    // Like write, for background threads outside of the async runtime. Must not be called from
+    // async code.
+    pub fn write_blocking<T>(
+        &self,
+        f: impl FnOnce(&mut Database) -> Result<T, ServerError>,
+    ) -> Result<T, ServerError> {
+        self.inner.run_write(f)
+    }
+}
+
+impl Inner {
+    fn run_read<T>(
+        &self,
+        f: impl FnOnce(&mut Database) -> Result<T, ServerError>,
+    ) -> Result<T, ServerError> {
+        let idle = self
+            .readers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let mut reader = match idle {
+            Some(reader) => reader,
+            None => Database::open_reader(self.archive_dir.clone())
+                .map_err(|e| e.context("Unable to open a database reader"))?,
+        };
+        // A reader whose work panicked is closed instead of reused
+        let result =
+            catch_unwind(AssertUnwindSafe(|| f(&mut reader))).map_err(|p| panicked("read", p))?;
+        self.readers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(reader);
+        result
+    }
+
+    fn run_write<T>(
+        &self,
+        f: impl FnOnce(&mut Database) -> Result<T, ServerError>,
+    ) -> Result<T, ServerError> {
+        // Panics are caught below, so the lock is only poisoned if recovering failed as well
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        // Work that failed or panicked halfway must not leave a transaction open
+        match catch_unwind(AssertUnwindSafe(|| f(&mut writer))) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(e)) => {
+                writer.rollback();
+                Err(e)
+            }
+            Err(panic) => {
+                writer.rollback();
+                Err(panicked("write", panic))
+            }
+        }
+    }
+}
+
+fn panicked(kind: &str, panic: Box<dyn Any + Send>) -> ServerError {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Unknown panic".to_string());
+    ServerError::Internal(anyhow!("Database {kind} panicked: {message}"))
+}
@@ -1,72 +1,581 @@
-use std::sync::{Arc, Mutex};
+use std::{collections::HashSet, io, iter::once, sync::Arc};
 
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
 use axum::{
     body::{Body, Bytes},
+    extract::Request,
     http::header,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
+};
+use common::{
+    api::{DownloadForm, SignedDownload},
+    archive_job::CollectionImport,
+    candidate::{Candidate, CollectionCandidate},
+    source::Source,
+    track::{Track, TrackQuery, TrackUpdate},
+    user::{validate_password, NewUser, Role, User},
 };
-use common::candidate::Candidate;
 use crossbeam::channel::Sender;
-use tokio_util::io::ReaderStream;
+use serde::de::DeserializeOwned;
+use tokio::{fs::File, io::DuplexStream};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_util::{compat::FuturesAsyncWriteCompatExt, io::ReaderStream};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::error;
 
-use crate::{database::Database, TRACK_DIR};
+use crate::{
+    archiver::{expand_collection, write_audio_tags, ExpandedCollection},
+    auth::{hash_password, require_role, Credential, DownloadSigner},
+    database::Database,
+    error::ServerError,
+    pool::DatabasePool,
+    trash, TRACK_DIR,
+};
 
-pub async fn get_all_tracks(database: Arc<Mutex<Database>>) -> String {
-    let tracks = database.lock().unwrap().all_tracks();
-    serde_json::to_string(&tracks).unwrap()
+pub async fn get_all_tracks(database: DatabasePool) -> Result<String, ServerError> {
+    let tracks = database.read(|db| db.all_tracks()).await?;
+    Ok(serde_json::to_string(&tracks).unwrap())
 }
 
-pub async fn archive_track(sender: Sender<Candidate>, body: Bytes) -> Result<(), String> {
-    let candidate: Candidate = match serde_json::from_slice(&body) {
-        Ok(candidate) => candidate,
-        Err(e) => return Err(e.to_string()),
-    };
-    sender.send(candidate).unwrap();
-    Ok(())
+pub async fn query_tracks(
+    database: DatabasePool,
+    query: TrackQuery,
+) -> Result<String, ServerError> {
+    let page = database.read(move |db| db.query_tracks(&query)).await?;
+    Ok(serde_json::to_string(&page).unwrap())
+}
+
+// Changes title and artists of a track, its tags and its file name
+pub async fn update_track(
+    database: DatabasePool,
+    user: User,
+    id: u32,
+    body: Bytes,
+) -> Result<String, ServerError> {
+    require_role(&user, Role::Contributor)?;
+    let update: TrackUpdate = parse_json(&body)?;
+    let update = update.validated().map_err(ServerError::bad_request)?;
+
+    // The files are changed while holding the writer, so no other update can interfere
+    let track = database
+        .write(move |db| update_track_files(db, id, update))
+        .await?;
+    Ok(serde_json::to_string(&track).unwrap())
+}
+
+// Retags and renames the file of the track together with the database update
+fn update_track_files(
+    database: &mut Database,
+    id: u32,
+    update: TrackUpdate,
+) -> Result<Track, ServerError> {
+    let old_track = database.get_tracks(once(id))?.pop().unwrap();
+    let mut track = old_track.clone();
+    track.title = update.title;
+    track.artists = update.artists;
+
+    let mut old_path = TRACK_DIR.clone();
+    old_path.push(old_track.file_name());
+    let mut new_path = TRACK_DIR.clone();
+    new_path.push(track.file_name());
+    let renamed = new_path != old_path;
+    if renamed && new_path.exists() {
+        return Err(ServerError::Conflict(format!(
+            "A track with the file name {} already exists",
+            track.file_name()
+        )));
+    }
+
+    // The tags are rewritten on a copy, which only replaces the original
+    // file once the database update is committed as well
+    let mut tmp_path = TRACK_DIR.clone();
+    tmp_path.push(format!(".{id}.m4a.tmp"));
+    let result = std::fs::copy(&old_path, &tmp_path)
+        .map_err(ServerError::from)
+        .and_then(|_| Ok(write_audio_tags(&tmp_path, &track.title, &track.artists)?))
+        .and_then(|_| {
+            database.update_track(&track, |files| {
+                files.remove(&old_path)?;
+                files.rename(&tmp_path, &new_path)
+            })
+        });
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(track)
+}
+
+// Moves the tracks into the trash
+pub async fn delete_tracks(
+    database: DatabasePool,
+    user: User,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Contributor)?;
+    let ids = parse_ids(&body)?;
+    database
+        .write(move |db| trash::trash_tracks(db, &ids))
+        .await
+}
+
+pub async fn get_trash(database: DatabasePool) -> Result<String, ServerError> {
+    let tracks = database.read(|db| db.trashed_tracks()).await?;
+    Ok(serde_json::to_string(&tracks).unwrap())
+}
+
+pub async fn restore_tracks(
+    database: DatabasePool,
+    user: User,
+    body: Bytes,
+) -> Result<String, ServerError> {
+    require_role(&user, Role::Contributor)?;
+    let ids = parse_ids(&body)?;
+    let tracks = database
+        .write(move |db| trash::restore_tracks(db, &ids))
+        .await?;
+    Ok(serde_json::to_string(&tracks).unwrap())
+}
+
+pub async fn purge_tracks(
+    database: DatabasePool,
+    user: User,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Admin)?;
+    let ids = parse_ids(&body)?;
+    database
+        .write(move |db| trash::purge_tracks(db, &ids))
+        .await
+}
+
+// Parses a json list of ids, duplicates are removed
+fn parse_ids(body: &Bytes) -> Result<Vec<u32>, ServerError> {
+    let mut ids: Vec<u32> = parse_json(body)?;
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+fn parse_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, ServerError> {
+    serde_json::from_slice(body).map_err(ServerError::bad_request)
 }
 
-pub async fn download_tracks(database: Arc<Mutex<Database>>, body: String) -> impl IntoResponse {
-    let ids_encoded = body
-        .trim()
-        .trim_start_matches("ids=%5B")
-        .trim_end_matches("%5D");
-    let ids_encoded = format!("[{}]", ids_encoded);
-    let ids: Vec<u32> = match serde_json::from_str(&ids_encoded) {
-        Ok(ids) => ids,
-        Err(e) => return Err(e.to_string()),
+pub async fn archive_track(
+    database: DatabasePool,
+    sender: Sender<()>,
+    user: User,
+    body: Bytes,
+) -> Result<String, ServerError> {
+    require_role(&user, Role::Contributor)?;
+    let candidate: Candidate = parse_json(&body)?;
+    let candidate = candidate.validated().map_err(ServerError::bad_request)?;
+    let job_id = database
+        .write(move |db| db.insert_archive_job(&candidate, None))
+        .await?;
+    wake_archiver(&sender);
+    Ok(serde_json::to_string(&job_id).unwrap())
+}
+
+// Queues an archive job for every track of a playlist or channel
+pub async fn archive_collection(
+    database: DatabasePool,
+    sender: Sender<()>,
+    user: User,
+    body: Bytes,
+) -> Result<String, ServerError> {
+    require_role(&user, Role::Contributor)?;
+    let collection: CollectionCandidate = parse_json(&body)?;
+    let collection = collection.validated().map_err(ServerError::bad_request)?;
+
+    let url = collection.url.clone();
+    let expanded = tokio::task::spawn_blocking(move || expand_collection(&url))
+        .await
+        .map_err(|e| ServerError::Internal(e.into()))?
+        .map_err(|e| ServerError::BadGateway(e.to_string()))?;
+
+    let import = database
+        .write(move |db| import_collection(db, collection, expanded))
+        .await?;
+    if !import.job_ids.is_empty() {
+        wake_archiver(&sender);
+    }
+    Ok(serde_json::to_string(&import).unwrap())
+}
+
+// Sending only fails if the archiver stopped. The jobs are queued in the database already, so
+// they still run once the server is restarted.
+fn wake_archiver(sender: &Sender<()>) {
+    if let Err(e) = sender.send(()) {
+        error!("Unable to wake up the archiver: {e}");
+    }
+}
+
+// Queues the tracks of the collection which are not archived yet
+fn import_collection(
+    db: &mut Database,
+    collection: CollectionCandidate,
+    expanded: ExpandedCollection,
+) -> Result<CollectionImport, ServerError> {
+    let playlist_id = if collection.create_playlist {
+        let name = expanded.title.as_deref().unwrap_or(&collection.url);
+        Some(db.create_playlist(name)?)
+    } else {
+        None
     };
-    let tracks = match database.lock().unwrap().get_tracks(ids.into_iter()) {
-        Ok(tracks) => tracks,
-        Err(e) => return Err(e.to_string()),
+    let mut import = CollectionImport {
+        job_ids: Vec::new(),
+        already_archived: 0,
+        unsupported: 0,
+        playlist_id,
     };
-
-    // Handle case of 1 track
-    let (file, filename) = match tracks.len() {
-        1 => {
-            let track = &tracks[0];
-            let file_name = track.file_name();
-            let mut path = TRACK_DIR.clone();
-            path.push(&file_name);
-            (
-                match tokio::fs::File::open(path).await {
-                    Ok(file) => file,
-                    Err(err) => return Err(err.to_string()),
-                },
-                file_name,
-            )
+    let mut seen = HashSet::new();
+    for url in expanded.urls {
+        let Some(source) = Source::recognize(&url) else {
+            import.unsupported += 1;
+            continue;
+        };
+        if !seen.insert(source.url.clone()) {
+            continue;
         }
-        _ => todo!(),
-    };
+        // Archived tracks join the playlist right away, new ones once their job succeeds. The
+        // jobs keep their position, so the playlist ends up in the order of the collection.
+        let position = import.already_archived + import.job_ids.len() as u32;
+        if let Some(track_id) = db.archived_track_id(&source.url)? {
+            import.already_archived += 1;
+            if let Some(playlist_id) = playlist_id {
+                db.add_playlist_tracks(playlist_id, &[track_id])?;
+            }
+            continue;
+        }
+        let candidate = Candidate {
+            url: source.url,
+            title: None,
+            artists: Vec::new(),
+        };
+        import
+            .job_ids
+            .push(db.insert_archive_job(&candidate, playlist_id.map(|id| (id, position)))?);
+    }
+    Ok(import)
+}
 
-    // convert the `AsyncRead` into a `Stream`
-    let stream = ReaderStream::new(file);
-    // convert the `Stream` into an `axum::body::HttpBody`
-    let body = Body::from_stream(stream);
+pub async fn get_archive_jobs(database: DatabasePool) -> Result<String, ServerError> {
+    let jobs = database.read(|db| db.all_archive_jobs()).await?;
+    Ok(serde_json::to_string(&jobs).unwrap())
+}
+
+pub async fn get_archive_job(database: DatabasePool, id: u32) -> Result<String, ServerError> {
+    match database.read(move |db| db.archive_job(id)).await? {
+        Some(job) => Ok(serde_json::to_string(&job).unwrap()),
+        None => Err(ServerError::NotFound(format!(
+            "No archive job with id {id} exists"
+        ))),
+    }
+}
 
-    let headers = [(
-        header::CONTENT_DISPOSITION,
-        format!("attachment; filename=\"{}\"", filename),
-    )];
+pub async fn download_tracks(
+    database: DatabasePool,
+    form: DownloadForm,
+) -> Result<impl IntoResponse, ServerError> {
+    let mut ids = form.ids().map_err(ServerError::bad_request)?;
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.is_empty() {
+        return Err(ServerError::bad_request("No track ids given"));
+    }
+
+    let tracks = database
+        .read(move |db| db.get_tracks(ids.into_iter()))
+        .await?;
+
+    // Open all files up front, so a missing file is reported before streaming starts
+    let mut files = Vec::with_capacity(tracks.len());
+    for track in &tracks {
+        let file_name = track.file_name();
+        let mut path = TRACK_DIR.clone();
+        path.push(&file_name);
+        files.push((file_name, File::open(path).await?));
+    }
+
+    let (content_type, filename, body) = if files.len() == 1 {
+        let (file_name, file) = files.pop().unwrap();
+        // convert the `AsyncRead` into a `Stream`
+        let stream = ReaderStream::new(file);
+        // convert the `Stream` into an `axum::body::HttpBody`
+        ("audio/mp4", file_name, Body::from_stream(stream))
+    } else {
+        (
+            "application/zip",
+            "harmony.zip".to_string(),
+            zip_body(files),
+        )
+    };
+
+    let headers = [
+        (header::CONTENT_TYPE, content_type.to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        ),
+    ];
 
     Ok((headers, body))
 }
+
+pub async fn create_download_url(
+    signer: Arc<DownloadSigner>,
+    body: Bytes,
+) -> Result<String, ServerError> {
+    let ids = parse_ids(&body)?;
+    if ids.is_empty() {
+        return Err(ServerError::bad_request("No track ids given"));
+    }
+    Ok(serde_json::to_string(&signer.sign(&ids)).unwrap())
+}
+
+// Served without a credential, the signature proves a user was allowed to download the tracks
+pub async fn download_signed_tracks(
+    database: DatabasePool,
+    signer: Arc<DownloadSigner>,
+    download: SignedDownload,
+) -> Result<impl IntoResponse, ServerError> {
+    signer.verify(&download)?;
+    download_tracks(database, DownloadForm { ids: download.ids }).await
+}
+
+// Serves the audio file of a track, supports range requests so players can seek
+pub async fn stream_track(
+    database: DatabasePool,
+    id: u32,
+    request: Request,
+) -> Result<Response, ServerError> {
+    let track = database
+        .read(move |db| db.get_tracks(once(id)))
+        .await?
+        .pop()
+        .unwrap();
+    let mut path = TRACK_DIR.clone();
+    path.push(track.file_name());
+
+    let response = ServeFile::new_with_mime(path, &"audio/mp4".parse().unwrap())
+        .oneshot(request)
+        .await
+        .unwrap();
+    Ok(response.into_response())
+}
+
+// Writes the files into a zip archive on the fly, the other end of the duplex
+// stream is sent to the client while the archive is being built.
+// Streams a zip of the files. The zip is written while it is sent, so a failure can not change
+// the status anymore. It fails the body instead, which aborts the response so the client does
+// not take the truncated zip for a complete one.
+fn zip_body(files: Vec<(String, File)>) -> Body {
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+        let (writer, reader) = tokio::io::duplex(64 * 1024);
+        let send_chunks = async {
+            let mut chunks = ReaderStream::new(reader);
+            while let Some(chunk) = chunks.next().await {
+                if sender.send(chunk).await.is_err() {
+                    // The client went away
+                    break;
+                }
+            }
+        };
+        let (written, ()) = tokio::join!(write_zip(files, writer), send_chunks);
+        if let Err(e) = written {
+            error!("Unable to write zip archive: {e}");
+            let _ = sender.send(Err(io::Error::other(e))).await;
+        }
+    });
+    Body::from_stream(ReceiverStream::new(receiver))
+}
+
+async fn write_zip(files: Vec<(String, File)>, writer: DuplexStream) -> anyhow::Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for (file_name, mut file) in files {
+        // Audio is already compressed, so the files are only stored
+        let entry = ZipEntryBuilder::new(file_name.into(), Compression::Stored);
+        let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
+        tokio::io::copy(&mut file, &mut entry_writer).await?;
+        entry_writer.into_inner().close().await?;
+    }
+    zip.close().await?;
+    Ok(())
+}
+
+pub async fn get_playlists(database: DatabasePool) -> Result<String, ServerError> {
+    let playlists = database.read(|db| db.all_playlists()).await?;
+    Ok(serde_json::to_string(&playlists).unwrap())
+}
+
+pub async fn create_playlist(
+    database: DatabasePool,
+    user: User,
+    body: Bytes,
+) -> Result<String, ServerError> {
+    require_role(&user, Role::Contributor)?;
+    let name = playlist_name(&body)?;
+    let id = database.write(move |db| db.create_playlist(&name)).await?;
+    Ok(serde_json::to_string(&id).unwrap())
+}
+
+pub async fn rename_playlist(
+    database: DatabasePool,
+    user: User,
+    id: u32,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Contributor)?;
+    let name = playlist_name(&body)?;
+    database
+        .write(move |db| db.rename_playlist(id, &name))
+        .await
+}
+
+pub async fn delete_playlist(
+    database: DatabasePool,
+    user: User,
+    id: u32,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Contributor)?;
+    database.write(move |db| db.delete_playlist(id)).await
+}
+
+pub async fn order_playlists(
+    database: DatabasePool,
+    user: User,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Contributor)?;
+    let ids: Vec<u32> = parse_json(&body)?;
+    database.write(move |db| db.order_playlists(&ids)).await
+}
+
+pub async fn add_playlist_tracks(
+    database: DatabasePool,
+    user: User,
+    id: u32,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Contributor)?;
+    edit_playlist_tracks(database, id, body, Database::add_playlist_tracks).await
+}
+
+pub async fn remove_playlist_tracks(
+    database: DatabasePool,
+    user: User,
+    id: u32,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Contributor)?;
+    edit_playlist_tracks(database, id, body, Database::remove_playlist_tracks).await
+}
+
+pub async fn order_playlist_tracks(
+    database: DatabasePool,
+    user: User,
+    id: u32,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Contributor)?;
+    edit_playlist_tracks(database, id, body, Database::order_playlist_tracks).await
+}
+
+// Parses a json list of track ids and applies edit to the playlist
+async fn edit_playlist_tracks(
+    database: DatabasePool,
+    id: u32,
+    body: Bytes,
+    edit: impl FnOnce(&mut Database, u32, &[u32]) -> Result<(), ServerError> + Send + 'static,
+) -> Result<(), ServerError> {
+    let track_ids: Vec<u32> = parse_json(&body)?;
+    database.write(move |db| edit(db, id, &track_ids)).await
+}
+
+// Parses and trims the name, which must not be empty
+fn playlist_name(body: &Bytes) -> Result<String, ServerError> {
+    let name: String = parse_json(body)?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ServerError::bad_request("Playlist name must not be empty"));
+    }
+    Ok(name.to_string())
+}
+
+pub async fn get_current_user(user: User) -> String {
+    serde_json::to_string(&user).unwrap()
+}
+
+pub async fn get_users(database: DatabasePool, user: User) -> Result<String, ServerError> {
+    require_role(&user, Role::Admin)?;
+    let users = database.read(|db| db.all_users()).await?;
+    Ok(serde_json::to_string(&users).unwrap())
+}
+
+pub async fn create_user(
+    database: DatabasePool,
+    user: User,
+    body: Bytes,
+) -> Result<String, ServerError> {
+    require_role(&user, Role::Admin)?;
+    let new_user: NewUser = parse_json(&body)?;
+    let new_user = new_user.validated().map_err(ServerError::bad_request)?;
+    let password_hash = hash_password_blocking(new_user.password.clone()).await?;
+    let created = database
+        .write(move |db| db.insert_user(&new_user, &password_hash))
+        .await?;
+    Ok(serde_json::to_string(&created).unwrap())
+}
+
+pub async fn set_user_role(
+    database: DatabasePool,
+    user: User,
+    id: u32,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    require_role(&user, Role::Admin)?;
+    let role: Role = parse_json(&body)?;
+    database.write(move |db| db.set_user_role(id, role)).await
+}
+
+// Admins may set every password, everyone else only their own
+// Signs the user out everywhere else, in case the old password leaked
+pub async fn set_user_password(
+    database: DatabasePool,
+    user: User,
+    credential: Credential,
+    id: u32,
+    body: Bytes,
+) -> Result<(), ServerError> {
+    if user.id != id {
+        require_role(&user, Role::Admin)?;
+    }
+    let password: String = parse_json(&body)?;
+    validate_password(&password).map_err(ServerError::bad_request)?;
+    let password_hash = hash_password_blocking(password).await?;
+    let (keep_session, keep_access_token) = match credential {
+        _ if user.id != id => (None, None),
+        Credential::Session(session) => (Some(session.id), None),
+        Credential::AccessToken(token) => (None, Some(token.id)),
+    };
+    database
+        .write(move |db| db.set_user_password(id, &password_hash, keep_session, keep_access_token))
+        .await
+}
+
+pub async fn delete_user(database: DatabasePool, user: User, id: u32) -> Result<(), ServerError> {
+    require_role(&user, Role::Admin)?;
+    database.write(move |db| db.delete_user(id)).await
+}
+
+// Hashing is slow on purpose, so it must not block the runtime
+async fn hash_password_blocking(password: String) -> Result<String, ServerError> {
+    Ok(
+        tokio::task::spawn_blocking(move || hash_password(&password))
+            .await
+            .map_err(|e| ServerError::Internal(e.into()))??,
+    )
+}
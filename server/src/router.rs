@@ -0,0 +1,115 @@
+use axum::{
+    extract::{OriginalUri, Request},
+    handler::Handler,
+    http::{header, HeaderValue},
+    middleware::{self, Next},
+    routing::{on, MethodFilter},
+    Router,
+};
+use common::{
+    api::{Endpoint, Method},
+    token::Scope,
+};
+use tracing::debug;
+
+/// A method and path the router serves an endpoint at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub method: Method,
+    pub path: &'static str,
+    // See Endpoint::SCOPE
+    pub scope: Option<Scope>,
+    pub deprecated: bool,
+}
+
+/// Router that serves endpoints at their routes and remembers which it serves, so the auth
+/// middleware can look up their scopes and the OpenAPI document can be checked against them
+#[derive(Default)]
+pub struct ApiRouter {
+    router: Router,
+    routes: Vec<Route>,
+}
+
+impl ApiRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn endpoint<E, T>(mut self, handler: impl Handler<T, ()>) -> Self
+    where
+        E: Endpoint,
+        T: 'static,
+    {
+        let scope = E::SCOPE;
+        if let Some((method, path)) = E::DEPRECATED_ROUTE {
+            let route = E::ROUTE;
+            let deprecated = handler.clone().layer(middleware::from_fn(
+                move |request: Request, next: Next| async move {
+                    debug!("Deprecated route {path} was used");
+                    let successor = format!("{}{route}", nest_prefix(&request));
+                    let mut response = next.run(request).await;
+                    let headers = response.headers_mut();
+                    headers.insert("Deprecation", HeaderValue::from_static("true"));
+                    if let Ok(link) =
+                        HeaderValue::from_str(&format!("<{successor}>; rel=\"successor-version\""))
+                    {
+                        headers.insert(header::LINK, link);
+                    }
+                    response
+                },
+            ));
+            self.router = self
+                .router
+                .route(path, on(method_filter(method), deprecated));
+            self.routes.push(Route {
+                method,
+                path,
+                scope,
+                deprecated: true,
+            });
+        }
+        self.router = self
+            .router
+            .route(E::ROUTE, on(method_filter(E::METHOD), handler));
+        self.routes.push(Route {
+            method: E::METHOD,
+            path: E::ROUTE,
+            scope,
+            deprecated: false,
+        });
+        self
+    }
+
+    // Changes the router, e.g. to add a layer to the endpoints served so far
+    pub fn map_router(mut self, f: impl FnOnce(Router) -> Router) -> Self {
+        self.router = f(self.router);
+        self
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    pub fn into_router(self) -> Router {
+        self.router
+    }
+}
+
+fn method_filter(method: Method) -> MethodFilter {
+    match method {
+        Method::Get => MethodFilter::GET,
+        Method::Post => MethodFilter::POST,
+        Method::Put => MethodFilter::PUT,
+        Method::Patch => MethodFilter::PATCH,
+        Method::Delete => MethodFilter::DELETE,
+    }
+}
+
+/// Prefix the router is nested under, which MatchedPath includes but the routes do not
+pub fn nest_prefix(request: &Request) -> &str {
+    request
+        .extensions()
+        .get::<OriginalUri>()
+        .and_then(|original| original.path().strip_suffix(request.uri().path()))
+        .unwrap_or_default()
+}
@@ -0,0 +1,128 @@
+use std::{path::PathBuf, time::Duration};
+
+use chrono::Utc;
+use common::track::Track;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    database::{url_archived_again, Database},
+    error::ServerError,
+    pool::DatabasePool,
+    TRACK_DIR, TRASH_DIR, TRASH_RETENTION,
+};
+
+// Moves the tracks into the trash, their files are moved into TRASH_DIR
+pub fn trash_tracks(db: &mut Database, ids: &[u32]) -> Result<(), ServerError> {
+    let tracks = db.get_tracks(ids.iter().copied())?;
+    for track in tracks {
+        let track_path = track_path(&track);
+        db.trash_track(&track, |files| {
+            if track_path.exists() {
+                files.rename(&track_path, &trashed_path(track.id))?;
+            } else {
+                warn!(
+                    "Trashing track {} without a file at {:?}",
+                    track.id, track_path
+                );
+            }
+            Ok(())
+        })?;
+        debug!("Trashed track {}", track.id);
+    }
+    Ok(())
+}
+
+// Moves the tracks out of the trash back into the library
+pub fn restore_tracks(db: &mut Database, ids: &[u32]) -> Result<Vec<Track>, ServerError> {
+    for id in ids {
+        let Some(trashed) = db.trashed_track(*id)? else {
+            return Err(ServerError::NotFound(format!(
+                "No track with id {id} is in the trash"
+            )));
+        };
+        if db.is_track_archived(trashed.track.url())? {
+            return Err(url_archived_again(&trashed.track));
+        }
+    }
+
+    let mut tracks = Vec::with_capacity(ids.len());
+    for id in ids {
+        let track = db.restore_track(*id, |files, track| {
+            let track_path = track_path(track);
+            if track_path.exists() {
+                return Err(ServerError::Conflict(format!(
+                    "A track with the file name {} already exists",
+                    track.file_name()
+                )));
+            }
+            let trashed_path = trashed_path(track.id);
+            if trashed_path.exists() {
+                files.rename(&trashed_path, &track_path)?;
+            } else {
+                warn!(
+                    "Restoring track {} without a file at {:?}",
+                    track.id, trashed_path
+                );
+            }
+            Ok(())
+        })?;
+        debug!("Restored track {}", track.id);
+        tracks.push(track);
+    }
+    Ok(tracks)
+}
+
+// Deletes the tracks and their files from the trash for good
+pub fn purge_tracks(db: &mut Database, ids: &[u32]) -> Result<(), ServerError> {
+    for id in ids {
+        if db.trashed_track(*id)?.is_none() {
+            return Err(ServerError::NotFound(format!(
+                "No track with id {id} is in the trash"
+            )));
+        }
+    }
+
+    for id in ids {
+        db.purge_trashed_track(*id, |files| {
+            let path = trashed_path(*id);
+            if path.exists() {
+                files.remove(&path)?;
+            }
+            Ok(())
+        })?;
+        debug!("Purged track {id}");
+    }
+    Ok(())
+}
+
+// Regularly purges tracks that have been in the trash for longer than TRASH_RETENTION
+pub fn trash_purger_task(db: DatabasePool) {
+    loop {
+        let purged = db.write_blocking(|db| {
+            let expired = db.trashed_before(Utc::now().naive_utc() - *TRASH_RETENTION)?;
+            if !expired.is_empty() {
+                info!("Purging {} expired tracks from the trash", expired.len());
+                purge_tracks(db, &expired)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = purged {
+            error!("Unable to purge expired tracks from the trash: {e:#}");
+        }
+
+        std::thread::sleep(Duration::from_secs(60 * 60));
+    }
+}
+
+fn track_path(track: &Track) -> PathBuf {
+    let mut path = TRACK_DIR.clone();
+    path.push(track.file_name());
+    path
+}
+
+// Trashed files are named by id, so tracks with the same file name can be in the trash at once
+fn trashed_path(id: u32) -> PathBuf {
+    let mut path = TRASH_DIR.clone();
+    path.push(format!("{}.m4a", id));
+    path
+}